@@ -1,5 +1,8 @@
 #![cfg_attr(not(test), no_std)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum FileSystemResult<T: Copy + Clone> {
     Ok(T),
@@ -15,6 +18,25 @@ impl<T: Copy + Clone> FileSystemResult<T> {
     }
 }
 
+impl<T: Copy + Clone> From<FileSystemResult<T>> for Result<T, FileSystemError> {
+    fn from(result: FileSystemResult<T>) -> Self {
+        match result {
+            FileSystemResult::Ok(v) => Ok(v),
+            FileSystemResult::Err(e) => Err(e),
+        }
+    }
+}
+
+/// Unwraps a `FileSystemResult`, returning early from the enclosing function on `Err`.
+macro_rules! fs_try {
+    ($result:expr) => {
+        match $result {
+            FileSystemResult::Ok(v) => v,
+            FileSystemResult::Err(e) => return FileSystemResult::Err(e),
+        }
+    };
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum FileSystemError {
     FileNotFound,
@@ -27,1241 +49,6429 @@ pub enum FileSystemError {
     DiskFull,
     FileTooBig,
     FilenameTooLong,
+    EmptyFilename,
+    /// Names may not contain `/` or NUL.
+    InvalidFilename,
+    FileExists,
+    TooManyLinks,
+    NotASymlink,
+    TooManySymlinks,
+    PermissionDenied,
+    XattrNotFound,
+    /// Attribute names may not be empty.
+    InvalidXattrName,
+    XattrNameTooLong,
+    XattrValueTooLong,
+    XattrSpaceFull,
+    /// An update needs more metadata blocks than the journal holds, so none of it
+    /// was made. See `JOURNAL_CAPACITY` for which operations can hit this.
+    TransactionTooBig,
+    SnapshotNotFound,
+    SnapshotExists,
+    TooManySnapshots,
+    /// The file system is mounted read-only, e.g. to look at a snapshot.
+    ReadOnly,
+    /// The operation replaces the whole file system, so no file may be open.
+    FilesOpen,
+    /// The contents of `block` don't match the checksum recorded when it was written.
+    Corrupted {
+        block: usize,
+    },
+    /// The image has no superblock, or records a geometry that can't be laid out.
+    InvalidGeometry,
+    /// The disk holds a file system laid out differently: with other parameters, or
+    /// by a version from before the superblock. It's left untouched.
+    UnsupportedFormat,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct FileInfo<const MAX_BLOCKS: usize, const BLOCK_SIZE: usize> {
-    inode: Inode<MAX_BLOCKS, BLOCK_SIZE>,
-    inode_num: usize,
-    current_block: usize,
-    offset: usize,
-    writing: bool,
-    reading: bool,
-    block_buffer: [u8; BLOCK_SIZE],
+/// Source of the timestamps recorded in inodes. The file system only stores and
+/// compares the values, so any monotonic unit works.
+pub trait Clock {
+    fn now(&mut self) -> u32;
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct Inode<const MAX_BLOCKS: usize, const BLOCK_SIZE: usize> {
-    bytes_stored: u16,
-    blocks: [u8; MAX_BLOCKS],
+/// Default clock for targets without a time source: ticks once per reading, so
+/// timestamps still reflect the order of events.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CounterClock {
+    ticks: u32,
 }
 
-const INODE_FULL_BLOCK: usize = 0;
-const DATA_FULL_BLOCK: usize = INODE_FULL_BLOCK + 1;
-const INODE_TABLE_START: usize = DATA_FULL_BLOCK + 1;
-
-#[derive(core::fmt::Debug)]
-pub struct FileSystem<
-    const MAX_OPEN: usize,
-    const BLOCK_SIZE: usize,
-    const NUM_BLOCKS: usize,
-    const MAX_FILE_BLOCKS: usize,
-    const MAX_FILE_BYTES: usize,
-    const MAX_FILES_STORED: usize,
-    const MAX_FILENAME_BYTES: usize,
-> {
-    open: [Option<FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>>; MAX_OPEN],
-    disk: ramdisk::RamDisk<BLOCK_SIZE, NUM_BLOCKS>,
-    block_buffer: [u8; BLOCK_SIZE],
-    file_content_buffer: [u8; MAX_FILE_BYTES],
-    directory_buffer: [u8; MAX_FILE_BYTES],
-    open_inodes: [bool; MAX_FILES_STORED],
-}
-//<16, 64, 255, 8, 512, 32, 8>
-impl<
-        const MAX_OPEN: usize,
-        const BLOCK_SIZE: usize,
-        const NUM_BLOCKS: usize,
-        const MAX_FILE_BLOCKS: usize,
-        const MAX_FILE_BYTES: usize,
-        const MAX_FILES_STORED: usize,
-        const MAX_FILENAME_BYTES: usize,
-    >
-    FileSystem<
-        MAX_OPEN,
-        BLOCK_SIZE,
-        NUM_BLOCKS,
-        MAX_FILE_BLOCKS,
-        MAX_FILE_BYTES,
-        MAX_FILES_STORED,
-        MAX_FILENAME_BYTES,
-    >
-{
-    pub fn new(disk: ramdisk::RamDisk<BLOCK_SIZE, NUM_BLOCKS>) -> Self {
-        assert_eq!(MAX_FILE_BYTES, MAX_FILE_BLOCKS * BLOCK_SIZE);
-        assert!(NUM_BLOCKS <= u8::MAX as usize);
-        assert!(MAX_FILE_BYTES <= u16::MAX as usize);
-        let block_bits = BLOCK_SIZE * 8;
-        assert!(MAX_FILES_STORED <= block_bits);
-        assert!(MAX_FILES_STORED <= u16::MAX as usize);
-        let result = Self {
-            open: [None; MAX_OPEN],
-            disk,
-            block_buffer: [0; BLOCK_SIZE],
-            file_content_buffer: [0; MAX_FILE_BYTES],
-            open_inodes: [false; MAX_FILES_STORED],
-            directory_buffer: [0; MAX_FILE_BYTES],
-        };
-        assert!(result.num_inode_blocks() * 2 < NUM_BLOCKS);
-        assert!(result.num_data_blocks() <= block_bits);
-        assert_eq!(
-            result.num_data_blocks() + result.num_inode_blocks() + 2,
-            NUM_BLOCKS
-        );
-        assert!(result.num_inode_entries() <= u16::MAX as usize);
-        assert!(result.num_inode_blocks() <= MAX_FILE_BLOCKS);
-        result
+impl Clock for CounterClock {
+    fn now(&mut self) -> u32 {
+        self.ticks = self.ticks.wrapping_add(1);
+        self.ticks
     }
+}
 
-    pub fn max_file_size(&self) -> usize {
-        MAX_FILE_BLOCKS * BLOCK_SIZE
-    }
+/// Storage a `FileSystem` lives on: fixed-size blocks numbered from 0. A block write
+/// either completes or, if power fails during it, may leave that block torn.
+pub trait BlockDevice<const BLOCK_SIZE: usize> {
+    fn read(&mut self, block: usize, buffer: &mut [u8; BLOCK_SIZE]);
+    fn write(&mut self, block: usize, buffer: &[u8; BLOCK_SIZE]);
 
-    pub fn num_inode_bytes(&self) -> usize {
-        2 + MAX_FILE_BLOCKS
-    }
+    /// Makes every write issued so far durable. Between flushes, writes may reach
+    /// the storage in any order.
+    fn flush(&mut self) {}
 
-    pub fn inodes_per_block(&self) -> usize {
-        BLOCK_SIZE / self.num_inode_bytes()
-    }
+    /// Tells the device that blocks `start..start + count` no longer hold anything, so
+    /// flash can erase them ahead of time. They may read back as anything until they're
+    /// next written.
+    fn discard(&mut self, _start: usize, _count: usize) {}
+}
 
-    pub fn num_inode_blocks(&self) -> usize {
-        MAX_FILES_STORED / self.inodes_per_block()
+impl<const BLOCK_SIZE: usize, const NUM_BLOCKS: usize> BlockDevice<BLOCK_SIZE>
+    for ramdisk::RamDisk<BLOCK_SIZE, NUM_BLOCKS>
+{
+    fn read(&mut self, block: usize, buffer: &mut [u8; BLOCK_SIZE]) {
+        ramdisk::RamDisk::read(self, block, buffer)
     }
 
-    pub fn num_data_blocks(&self) -> usize {
-        NUM_BLOCKS - self.num_inode_blocks() - 2
+    fn write(&mut self, block: usize, buffer: &[u8; BLOCK_SIZE]) {
+        ramdisk::RamDisk::write(self, block, buffer)
     }
+}
 
-    pub fn num_inode_entries(&self) -> usize {
-        self.inodes_per_block() * self.num_inode_blocks() * self.num_inode_bytes()
-    }
+/// Write-back cache of the `CAPACITY` most recently used blocks of `D`. Written
+/// blocks stay dirty in the cache until they're evicted or `flush` is called.
+#[derive(Debug)]
+pub struct BlockCache<D, const BLOCK_SIZE: usize, const CAPACITY: usize> {
+    disk: D,
+    blocks: [[u8; BLOCK_SIZE]; CAPACITY],
+    /// Block held in each slot, if any.
+    tags: [Option<usize>; CAPACITY],
+    dirty: [bool; CAPACITY],
+    /// Value of `clock` when each slot was last used.
+    last_used: [u64; CAPACITY],
+    clock: u64,
+    hits: usize,
+    misses: usize,
+}
 
-    pub fn first_data_block(&self) -> usize {
-        2 + self.num_inode_blocks()
+impl<D: BlockDevice<BLOCK_SIZE>, const BLOCK_SIZE: usize, const CAPACITY: usize>
+    BlockCache<D, BLOCK_SIZE, CAPACITY>
+{
+    pub fn new(disk: D) -> Self {
+        assert!(CAPACITY > 0);
+        Self {
+            disk,
+            blocks: [[0; BLOCK_SIZE]; CAPACITY],
+            tags: [None; CAPACITY],
+            dirty: [false; CAPACITY],
+            last_used: [0; CAPACITY],
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
     }
-    
 
-
-    pub fn get_directory_buffer(&mut self) -> FileSystemResult<[u8; MAX_FILE_BYTES]>{
-        return FileSystemResult::Ok(self.directory_buffer);
+    /// Reads and writes served from the cache.
+    pub fn hits(&self) -> usize {
+        self.hits
     }
-    pub fn open_stuff(&mut self) -> [Option<FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>>; MAX_OPEN]{
-        return self.open
+
+    /// Reads and writes that had to claim a slot, evicting the least recently used block.
+    pub fn misses(&self) -> usize {
+        self.misses
     }
-    pub fn open_read(&mut self, filename: &str) -> FileSystemResult<usize> {
-        self.get_directory();
-        let mut namebuffer = ['\0'; MAX_FILENAME_BYTES];
-        for (i, c) in filename.chars().enumerate() {
-            namebuffer[i] = c
-        }
 
-        let mut name_spot = 0;
-        let mut char_spot = 0;
-        let mut name_flag = true;
-        let mut found_inode = false;
-        let mut ignore = false;
-        let mut count = 0;
+    /// Writes back every dirty block and gives back the underlying device.
+    pub fn into_inner(mut self) -> D {
+        self.flush();
+        self.disk
+    }
 
-        for i in self.directory_buffer{
-            if ignore {
-                if count % MAX_FILENAME_BYTES == 0 {
-                    char_spot = 0;
-                    name_spot += 1;
-                    ignore = false;
-                    name_flag = true;
-                    
-                }
-                    
-            } 
-            if !ignore{
-                if i as char != namebuffer[char_spot as usize % MAX_FILENAME_BYTES]{
-                    ignore = true;
-                }
-                char_spot +=1;
-                if char_spot == MAX_FILENAME_BYTES && name_flag{
-                    name_spot +=1;
-                    found_inode = true;
-                    break;
-                }
-            }
-            count +=1;
-        }
-        
-        if found_inode{
-            if self.open_inodes[name_spot]{
-                return FileSystemResult::Err(FileSystemError::AlreadyOpen);
+    /// Slot holding `block`, claiming the least recently used one on a miss. A
+    /// claimed slot is loaded from disk only if `load` is set.
+    fn slot(&mut self, block: usize, load: bool) -> usize {
+        self.clock += 1;
+        let slot = match self.tags.iter().position(|tag| *tag == Some(block)) {
+            Some(slot) => {
+                self.hits += 1;
+                slot
             }
-
-            let inode_start = name_spot*self.num_inode_bytes();
-            let data = ((self.file_content_buffer[inode_start] as u16)<<8) | self.file_content_buffer[inode_start+1] as u16;
-            let mut inode_blocks = [self.file_content_buffer[inode_start+2];MAX_FILE_BLOCKS];
-            let mut c = 1;
-            for block in self.file_content_buffer[inode_start+2]..self.file_content_buffer[inode_start+self.num_inode_bytes()]{
-                if !(inode_blocks.contains(&block)){
-                    inode_blocks[c] = block;
-                    c +=1;
+            None => {
+                self.misses += 1;
+                let slot = (0..CAPACITY)
+                    .min_by_key(|slot| self.last_used[*slot])
+                    .unwrap();
+                if let Some(evicted) = self.tags[slot] {
+                    if self.dirty[slot] {
+                        self.disk.write(evicted, &self.blocks[slot]);
+                    }
                 }
-            }
-            let inode_for_file_entry = Inode{
-                bytes_stored: data,
-                blocks: inode_blocks,
-            };
-            let mut new_buffer = [0; BLOCK_SIZE];
-            self.disk.read(inode_for_file_entry.blocks[0].into(), &mut new_buffer);
-            let file_table_entry: FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE> = FileInfo{
-            inode: inode_for_file_entry,
-            inode_num: name_spot,
-            current_block: inode_for_file_entry.blocks[0].into(),
-            offset: 0,
-            writing: false,
-            reading: true,
-            block_buffer: new_buffer
-            };
-            self.open_inodes[name_spot] = true;
-            let mut fd = 0;
-            for i in self.open{
-                if i.is_none() {
-                    self.open[fd] = Some(file_table_entry);
-                    break;
+                if load {
+                    self.disk.read(block, &mut self.blocks[slot]);
                 }
-                fd += 1
+                self.tags[slot] = Some(block);
+                self.dirty[slot] = false;
+                slot
             }
-            return FileSystemResult::Ok(fd); //check to see if self.open is empty when closing files!!
-        }
-        return  FileSystemResult::Err(FileSystemError::FileNotFound);
+        };
+        self.last_used[slot] = self.clock;
+        slot
+    }
+}
 
+impl<D: BlockDevice<BLOCK_SIZE>, const BLOCK_SIZE: usize, const CAPACITY: usize>
+    BlockDevice<BLOCK_SIZE> for BlockCache<D, BLOCK_SIZE, CAPACITY>
+{
+    fn read(&mut self, block: usize, buffer: &mut [u8; BLOCK_SIZE]) {
+        let slot = self.slot(block, true);
+        *buffer = self.blocks[slot];
     }
 
-    pub fn open_create(&mut self, filename: &str) -> FileSystemResult<usize> {
-        let mut buffer = [0; BLOCK_SIZE];
-        let mut buffer2: [u8; BLOCK_SIZE] = [0 ; BLOCK_SIZE];
-        let mut namebuffer = ['\0'; MAX_FILENAME_BYTES];
-        if filename.len() > MAX_FILENAME_BYTES {
-            return FileSystemResult::Err(FileSystemError::FilenameTooLong);
-        }
-        for (i, c) in filename.chars().enumerate() {
-            namebuffer[i] = c
-        }
-
-        self.disk.read(INODE_FULL_BLOCK, &mut buffer);
-        self.disk.read(DATA_FULL_BLOCK, &mut buffer2);
-
-        if buffer[0] & (1<<0) == 0 {
-            let active_blocks = 2 + self.num_inode_blocks(); // What does this do again??
-            for i in 0..active_blocks + 1{
-                // Did this thinking mfb was 8, so worked for that. not for 64
-                let block = i/8; //CHANGED TO MAX FILE BLOCKS
-                let bit = i%8;
-                buffer2[block] |=1<<bit;
-
-            }
-            buffer[0] = 1 << 0;
-            self.disk.write(DATA_FULL_BLOCK, &mut buffer2);
-            self.disk.write(INODE_FULL_BLOCK, &mut buffer);
-            let data_block = 2 + self.num_inode_blocks();
-            
-            let dir_inode: Inode<MAX_FILE_BLOCKS, BLOCK_SIZE> = Inode{
-                bytes_stored:0,
-                blocks: [data_block.try_into().unwrap();MAX_FILE_BLOCKS],
-            };
+    fn write(&mut self, block: usize, buffer: &[u8; BLOCK_SIZE]) {
+        let slot = self.slot(block, false);
+        self.blocks[slot] = *buffer;
+        self.dirty[slot] = true;
+    }
 
-            let first = (dir_inode.bytes_stored >> 8) as u8;
-            let second = dir_inode.bytes_stored as u8;
-            let mut inode_buffer = [0;BLOCK_SIZE];
-            self.disk.read(INODE_TABLE_START, &mut inode_buffer);
-            inode_buffer[0] = first;
-            inode_buffer[1] = second;
-            let mut count = 2;
-            for i in dir_inode.blocks{
-                inode_buffer[count] = i;
-                count +=1;
+    fn flush(&mut self) {
+        for slot in 0..CAPACITY {
+            if let (Some(block), true) = (self.tags[slot], self.dirty[slot]) {
+                self.disk.write(block, &self.blocks[slot]);
+                self.dirty[slot] = false;
             }
-            self.disk.write(INODE_TABLE_START, &mut inode_buffer);
         }
+        self.disk.flush();
+    }
 
-        let mut inode_buffer = [0;BLOCK_SIZE];
-        let mut icount = 0;
-        for i in 2..self.num_inode_blocks(){
-            self.disk.read(i, &mut inode_buffer);
-            for j in 0..BLOCK_SIZE{
-                self.file_content_buffer[j + (icount * BLOCK_SIZE)] = inode_buffer[j]; //CHECK HERE FIRST IF NO WORK
-
+    /// Drops cached copies of the discarded blocks, dirty or not, before passing the
+    /// discard on.
+    fn discard(&mut self, start: usize, count: usize) {
+        for slot in 0..CAPACITY {
+            if self.tags[slot].is_some_and(|block| (start..start + count).contains(&block)) {
+                self.tags[slot] = None;
+                self.dirty[slot] = false;
             }
-            icount +=1;
         }
+        self.disk.discard(start, count);
+    }
+}
 
-        let bcount = MAX_FILE_BLOCKS;
-        let mut dir_blocks = [0;MAX_FILE_BLOCKS];
-        let mut count = 0;
-        for i in self.file_content_buffer{
-            if count < bcount + 2{
-                if count > 1{
-                    if !dir_blocks.contains(&i){
-                        dir_blocks[count-2] = i;
-                    }
-                }
-                count +=1;
-            }
-            else{
-                break;
-            }
-        }
+/// Identity an operation is checked against. User 0 is the superuser and passes
+/// every permission check.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u16,
+    pub gid: u16,
+}
 
-        self.get_directory();
-        let mut name_spot = 0;
-        let mut char_spot = 0;
-        let mut name_flag = true;
-        let mut found_inode = false;
-        let mut ignore = false;
-        let mut count = 0;
-        let mut inode_num = 0;
-
-        for i in self.directory_buffer{
-            if ignore {
-                if count % MAX_FILENAME_BYTES == 0 {
-                    char_spot = 0;
-                    name_spot += 1;
-                    ignore = false;
-                    name_flag = true;
-                    
-                }
-                    
-            } 
-            if !ignore{
-                if i as char != namebuffer[char_spot as usize % MAX_FILENAME_BYTES]{
-                    ignore = true;
-                }
-                char_spot +=1;
-                if char_spot == MAX_FILENAME_BYTES && name_flag{
-                    name_spot +=1;
-                    found_inode = true;
-                    break;
-                }
-            }
-            count +=1;
-        }
+impl Credentials {
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+}
 
-        if found_inode{
-            inode_num = name_spot;
-        }
-        else{
-            inode_num = 0;
-        }
-        if inode_num != 0{
-            if self.open_inodes[inode_num]{
-                return FileSystemResult::Err(FileSystemError::AlreadyOpen)
-            }
-            let inode_start = inode_num * self.num_inode_bytes();
-            let mut data_buffer = [0;BLOCK_SIZE];
-            let mut c = 0;
-            let mut using = 0;
-            for i in inode_start..inode_start+ self.num_inode_bytes(){
-                if c < 2 {
-                    self.file_content_buffer[i as usize] = 0 as u8;
-                    } 
-                if c == 2{
-                    using = self.file_content_buffer[i as usize];
-                }
-                if c > 2{
-                    let block = i / 8; //CHANGED TO MAX FILE BLOCKS
-                    let bit = i % 8;
-                    data_buffer[block as usize] &= !(1 << bit);
-                    self.file_content_buffer[i as usize] = using;
-                }
-                c += 1;
-            }
-
-            self.file_content_buffer = self.write_to_inode_table(2);
-            self.disk.read(DATA_FULL_BLOCK, &mut data_buffer);
-            self.disk.write(DATA_FULL_BLOCK, &mut data_buffer);
-            let data = ((self.file_content_buffer[inode_start as usize] as u16)<<8) | self.file_content_buffer[inode_start as usize+1] as u16;
-            let mut inode_blocks = [0;MAX_FILE_BLOCKS];
-            let mut ic = 0;
-            for i in inode_start+2..inode_start+self.num_inode_bytes(){
-                if !(inode_blocks.contains(&(self.file_content_buffer[i as usize]))){
-                    inode_blocks[ic] = self.file_content_buffer[i as usize];
-                    ic +=1;
-                }
-            }
-            let inode_for_file_entry = Inode {
-                bytes_stored: data,
-                blocks: inode_blocks,
-            };   
-            let mut new_buffer = [0 as u8;BLOCK_SIZE]; 
-            self.disk.read(inode_for_file_entry.blocks[0].into(), &mut new_buffer);
-
-            let file_table_entry: FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE> = FileInfo{
-                inode: inode_for_file_entry,
-                inode_num: inode_num as usize,
-                current_block: inode_for_file_entry.blocks[0].into(),
-                offset: 0,
-                writing: false,
-                reading: false,
-                block_buffer: new_buffer
-            };
+/// Problems found by `FileSystem::fsck`, counted by kind.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Allocated inodes that no directory entry names and no descriptor holds open.
+    pub orphaned_inodes: usize,
+    /// Blocks marked used in the data bitmap that nothing points to.
+    pub unreferenced_blocks: usize,
+    /// Blocks something points to that the data bitmap calls free.
+    pub unmarked_blocks: usize,
+    /// Block pointers to a block already claimed elsewhere, or outside the disk.
+    pub duplicate_blocks: usize,
+    /// Directory entries naming an inode that isn't allocated.
+    pub dangling_names: usize,
+    /// Inodes whose link count disagrees with the number of names they have.
+    pub wrong_link_counts: usize,
+    /// Inodes whose size runs past what their block pointers, or inline area, can hold.
+    pub oversized_files: usize,
+    /// Data blocks whose reference count disagrees with the number of files sharing them.
+    pub wrong_refcounts: usize,
+}
 
-            let mut fd = 0;
-            for i in self.open{
-                if i.is_none(){
-                    self.open[fd] = Some(file_table_entry);
-                    break;
-                } 
-                fd+=1;
-                
-                
-            }
-            return FileSystemResult::Ok(fd);
-            
-        }
-        else{
-            if buffer2[BLOCK_SIZE -1] == u8::MAX{
-                return FileSystemResult::Err(FileSystemError::DiskFull)
-            }
-            self.disk.read(0,&mut buffer);
-            let inode_stuff = self.return_open_inode();
-            if inode_stuff[2] == MAX_FILES_STORED as u8{
-                return FileSystemResult::Err(FileSystemError::TooManyFiles)
-            }
-            let inode_num = inode_stuff[2];
-            buffer[inode_stuff[0] as usize] |= 1 << inode_stuff[1];
-            self.disk.write(0, &mut buffer);
-            self.disk.read(1, &mut buffer2);
-            let mut blocks = [0;2];
-            let data_block = self.return_open_data();
-            buffer2[data_block[0] as usize] |= 1 << data_block[1];
-            blocks[0] = data_block[2];
-            let inode_start = inode_num as u16 * self.num_inode_bytes() as u16;
-            let iblock = 2 + (inode_start / BLOCK_SIZE as u16);
-            self.disk.write(1, &mut buffer2);
-            let inode_blocks = [blocks[0]; MAX_FILE_BLOCKS];
-
-            let inode_for_file_entry = Inode {
-                bytes_stored: 0,
-                blocks: inode_blocks,
-            };
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        *self == FsckReport::default()
+    }
+}
 
-            let first = (inode_for_file_entry.bytes_stored >> 8) as u8;
-            let second = inode_for_file_entry.bytes_stored as u8;
-            self.file_content_buffer[inode_start as usize] = first;
-            self.file_content_buffer[inode_start as usize + 1] = second;
+/// File metadata reported by `FileSystem::stat`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub inode: usize,
+    pub file_type: FileType,
+    pub size: usize,
+    /// Data blocks allocated to the file. Holes and inline contents take none.
+    pub blocks: usize,
+    pub links: u8,
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub atime: u32,
+}
 
-            let mut cc = 2;
-            for i in inode_for_file_entry.blocks{
-                self.file_content_buffer[inode_start as usize + cc as usize] = i;
-                cc += 1;
-            }
+#[derive(Debug, Copy, Clone)]
+pub struct FileInfo<const MAX_BLOCKS: usize, const BLOCK_SIZE: usize> {
+    inode: Inode<MAX_BLOCKS>,
+    inode_num: usize,
+    current_block: usize,
+    offset: usize,
+    writing: bool,
+    reading: bool,
+    block_buffer: [u8; BLOCK_SIZE],
+}
+
+/// A name in the directory and the inode it refers to.
+#[derive(Debug, Copy, Clone)]
+struct Entry<'a> {
+    name: &'a [u8],
+    inode: usize,
+}
 
-            self.file_content_buffer = self.write_to_inode_table( iblock as usize);
+/// Walks the entries packed in a directory, stopping at the first one that runs past
+/// its end.
+#[derive(Debug, Copy, Clone)]
+struct Entries<'a> {
+    directory: &'a [u8],
+    offset: usize,
+}
 
-            let dir_index = MAX_FILENAME_BYTES *  (inode_num - 1) as usize;
-            let index_start = dir_index % BLOCK_SIZE;
-            
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
 
-            if BLOCK_SIZE - index_start < MAX_FILENAME_BYTES{
-                let new_dblock = self.return_open_data();
-                buffer2[new_dblock[0] as usize] |= 1 << new_dblock[1];
-                self.file_content_buffer = self.add_new_data_to_inode(0, new_dblock[2]);
-                self.disk.write(1, &buffer2);
+    fn next(&mut self) -> Option<Entry<'a>> {
+        let start = self.offset + ENTRY_HEADER_BYTES;
+        let header = self.directory.get(self.offset..start)?;
+        let end = start + header[0] as usize;
+        let name = self.directory.get(start..end)?;
+        self.offset = end;
+        Some(Entry {
+            name,
+            inode: u16::from_be_bytes([header[1], header[2]]) as usize,
+        })
+    }
+}
 
-            }
+/// The directory held in memory: its entries as stored in its blocks, and a hash index
+/// over their names. `FileSystem` keeps both in arrays and `DynFileSystem` in vectors
+/// sized from its geometry, with a byte and a bucket per byte the directory can hold.
+#[derive(Debug, Clone)]
+struct Directory<B, I> {
+    buffer: B,
+    /// Bytes of directory in `buffer`, or `None` until it's loaded.
+    len: Option<usize>,
+    index: I,
+}
 
-            let mut dir_blocks = [0; MAX_FILE_BLOCKS];
-            let mut ic = 0;
-            for i in self.file_content_buffer[2]..self.file_content_buffer[self.num_inode_bytes()]{
-                if !(dir_blocks.contains(&i)){
-                    dir_blocks[ic] = i;
-                    ic +=1;
-                }
-            }
+// A directory entry is a name length byte, a big-endian inode number and the UTF-8
+// name, packed one after another from the start of the directory.
+//
+// The index is an open-addressing hash table over the names in the loaded directory,
+// probed linearly. Each bucket holds an entry offset plus one, or 0 if empty. Entries
+// take at least four bytes and there's a bucket per directory byte, so it's never more
+// than a quarter full.
 
-            let mut bc = 0;
-            for i in dir_blocks{
-                if i == 0 as u8{
-                    break;
+impl<B: AsRef<[u8]> + AsMut<[u8]>, I: AsRef<[u16]> + AsMut<[u16]>> Directory<B, I> {
+    fn size(&self) -> usize {
+        self.len.unwrap_or(0)
+    }
 
-                }
-                let mut temp_buffer = [0;BLOCK_SIZE];
-                self.disk.read(i as usize, &mut temp_buffer);
-                let mut temp_count = 0;
-                for j in temp_buffer{
-                    self.directory_buffer[temp_count + (BLOCK_SIZE*bc)] = j;
-                    temp_count +=1;
-                }
-                bc +=1;
-            }
+    fn entry_len(&self, offset: usize) -> usize {
+        ENTRY_HEADER_BYTES + self.buffer.as_ref()[offset] as usize
+    }
 
-            let mut _count = 0;
-            for i in index_start..index_start + MAX_FILENAME_BYTES{
-                self.directory_buffer[i] = namebuffer[_count] as u8;
-                _count += 1;
-            }
+    fn entry_inode(&self, offset: usize) -> usize {
+        let buffer = self.buffer.as_ref();
+        u16::from_be_bytes([buffer[offset + 1], buffer[offset + 2]]) as usize
+    }
 
-            self.directory_buffer = self.write_to_dir(dir_blocks);
-            let mut block_buffer = [0 as u8;BLOCK_SIZE]; 
-            self.disk.read(blocks[0] as usize, &mut block_buffer);
+    fn entry_name(&self, offset: usize) -> &[u8] {
+        &self.buffer.as_ref()[offset + ENTRY_HEADER_BYTES..offset + self.entry_len(offset)]
+    }
 
-            let file_table_entry: FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE> = FileInfo{
-                inode: inode_for_file_entry,
-                inode_num: inode_num as usize,
-                current_block: blocks[0].into(),
-                offset: 0,
-                writing: false,
-                reading: false,
-                block_buffer: block_buffer
-            };
-            let mut fd = 0;
-                for i in self.open{
-                    if i.is_none(){
-                        self.open[fd] = Some(file_table_entry);
-                        break;
-                    }
-                    fd+=1;
-                }
-                self.open_inodes[inode_num as usize] = true;
-                return FileSystemResult::Ok(fd);
+    /// Whether a whole entry starts at `offset`. A damaged directory ends at the first
+    /// entry that runs past its end.
+    fn entry_fits(&self, offset: usize) -> bool {
+        let size = self.size();
+        offset + ENTRY_HEADER_BYTES <= size && offset + self.entry_len(offset) <= size
+    }
+
+    /// The entries of the loaded directory, in the order they're stored.
+    fn entries(&self) -> Entries<'_> {
+        Entries {
+            directory: &self.buffer.as_ref()[..self.size()],
+            offset: 0,
         }
-            
-        
     }
-    
 
+    fn index_bucket(&self, name: &[u8]) -> usize {
+        crc32(0, name) as usize % self.index.as_ref().len()
+    }
 
+    fn index_insert(&mut self, offset: usize) {
+        let buckets = self.index.as_ref().len();
+        let mut bucket = self.index_bucket(self.entry_name(offset));
+        while self.index.as_ref()[bucket] != 0 {
+            bucket = (bucket + 1) % buckets;
+        }
+        self.index.as_mut()[bucket] = offset as u16 + 1;
+    }
 
-pub fn add_new_data_to_inode(&mut self, inode_num: u8, new_data_block : u8) -> [u8;MAX_FILE_BYTES] {
-    let mut buffer = [0; BLOCK_SIZE];
-    let inode_start = inode_num as u16 * self.num_inode_bytes() as u16;
-    let mut last_block = 0;
-    let mut index = (inode_start + 3) as u16;
-    let mut flag = false;
-    for i in (inode_start + 2) as u16..(inode_start as u16 + self.num_inode_bytes() as u16){
-        if !flag{
-            flag = true;
-            last_block = self.file_content_buffer[i as usize];
-        } else if flag && self.file_content_buffer[i as usize] == last_block{
-            break;
-        } else if flag {
-            last_block = self.file_content_buffer[i as usize];
-            index = i as u16;
+    fn rebuild_index(&mut self) {
+        self.index.as_mut().fill(0);
+        let mut offset = 0;
+        while self.entry_fits(offset) {
+            self.index_insert(offset);
+            offset += self.entry_len(offset);
         }
     }
-    self.file_content_buffer[index as usize] = new_data_block;
-    self.write_to_inode_table(2 + (inode_start as usize / BLOCK_SIZE))
-    
-}
 
-pub fn write_to_dir(&mut self, dir_blocks: [u8; MAX_FILE_BLOCKS]) -> [u8;MAX_FILE_BYTES]{
-    let mut dir_table_buffer = [0; BLOCK_SIZE];
-    
-    let mut blocks = [dir_blocks[0]; MAX_FILE_BLOCKS];
-    let mut num_of_blocks = 1;
-    for i in dir_blocks {
-        if blocks.contains(&i) {
-
-        } else{
-            blocks[num_of_blocks] = i;
-            num_of_blocks += 1;
-        }
-    }
-    let mut block = blocks[0];
-    let mut blocks_used = 0;
-    let mut count = 0;
-    for i in self.directory_buffer {   
-        if block == 0  {
-            break;
-        } else {
-            if BLOCK_SIZE - 1 == count{
-                self.disk.write(blocks[blocks_used].into(), &mut dir_table_buffer);
-                dir_table_buffer = [0; BLOCK_SIZE];
-                count = 0;
-                blocks_used += 1;
-                block = blocks[blocks_used];
-            } else {
-                dir_table_buffer[count] = i;
+    /// Finds the offset of the entry named `filename`; expects a loaded directory.
+    fn find_entry(&self, filename: &[u8]) -> Option<usize> {
+        let index = self.index.as_ref();
+        let mut bucket = self.index_bucket(filename);
+        loop {
+            match index[bucket] as usize {
+                0 => return None,
+                occupant if self.entry_name(occupant - 1) == filename => return Some(occupant - 1),
+                _ => bucket = (bucket + 1) % index.len(),
             }
-            count += 1
         }
-        
     }
-    return self.directory_buffer
-    
 }
 
-pub fn write_to_inode_table(&mut self, start_block: usize)  -> [u8;MAX_FILE_BYTES]{
-    let mut count = 0;
-    let mut inode_table_buffer = [0; BLOCK_SIZE];
-    let mut start = start_block;
-    let mut break_flag = false;
-    let total = MAX_FILES_STORED * self.num_inode_bytes();
-    let mut total_count = 0;
-    for i in self.file_content_buffer { 
-        if total_count >= total {
-            break;
-        }
-        if count + 1 == BLOCK_SIZE {
-            self.disk.write(start, &mut inode_table_buffer);
-            inode_table_buffer = [0; BLOCK_SIZE];
-            count = 0;
-            start += 1;
-        } else {
-            inode_table_buffer[count] = i;
-        }
-        count += 1;
-        total_count += 1;
-    }
-    return self.file_content_buffer;
+/// A file listed by `FileSystem::read_dir` or `DynFileSystem::read_dir`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirEntry<'a> {
+    pub name: &'a str,
+    pub inode: usize,
+    pub file_type: FileType,
+    pub len: usize,
+}
+
+/// Iterator over the directory returned by `FileSystem::read_dir` and
+/// `DynFileSystem::read_dir`. It walks the file system's copy of the directory in
+/// place, so nothing is copied out.
+#[derive(Debug, Copy, Clone)]
+pub struct ReadDir<'a> {
+    entries: Entries<'a>,
+    /// Type and size of every inode the directory names, indexed by inode number.
+    inodes: &'a [Option<(FileType, usize)>],
 }
 
-pub fn return_open_inode(&self) -> [u8; 3] {
-    //itable[0] & (1 << 0) == 0 
-    let mut buffer = [0; BLOCK_SIZE];
-    self.disk.read(0, &mut buffer);
-    let mut count = 0;
-    let mut block_bit:[u8; 3] = [0 as u8; 3];
-    for i in 0..BLOCK_SIZE {
-        for j in 0..8{
-            if buffer[i] & (1 << j) == 0 {
-                block_bit[0] = i as u8;
-                block_bit[1] = j;
-                block_bit[2] = count;
-                return block_bit;
+impl<'a> Iterator for ReadDir<'a> {
+    type Item = DirEntry<'a>;
+
+    fn next(&mut self) -> Option<DirEntry<'a>> {
+        loop {
+            let entry = self.entries.next()?;
+            let inode = self.inodes.get(entry.inode).copied().flatten();
+            if let (Ok(name), Some((file_type, len))) = (core::str::from_utf8(entry.name), inode) {
+                return Some(DirEntry {
+                    name,
+                    inode: entry.inode,
+                    file_type,
+                    len,
+                });
             }
-            count += 1;
         }
     }
-    //NEED TO HANDLE AN ERROR HERE
-    return [0,0,0];
 }
 
-pub fn return_open_data(&self) -> [u8; 3] {
-    //itable[0] & (1 << 0) == 0 
-    let mut buffer = [0; BLOCK_SIZE];
-    self.disk.read(1, &mut buffer);
-    let mut count = 0;
-    let mut block_bit:[u8; 3] = [0 as u8; 3];
-    for i in 0..BLOCK_SIZE {
-        for j in 0..8{
-            if buffer[i] & (1 << j) == 0 {
-                block_bit[0] = i as u8;
-                block_bit[1] = j;
-                block_bit[2] = count;
-                return block_bit;
-            }
-            count += 1;
-        }
+/// A snapshot listed by `FileSystem::snapshot_list`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo<const MAX_FILENAME_BYTES: usize> {
+    name: [u8; MAX_FILENAME_BYTES],
+    name_len: usize,
+    /// Clock reading when the snapshot was taken.
+    pub created: u32,
+}
+
+impl<const MAX_FILENAME_BYTES: usize> SnapshotInfo<MAX_FILENAME_BYTES> {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
     }
-    //NEED TO HANDLE AN ERROR HERE
-    return block_bit;
 }
 
+/// Iterator over the files matching a pattern, returned by `FileSystem::find`.
+#[derive(Debug, Copy, Clone)]
+pub struct Find<'a, 'p> {
+    entries: ReadDir<'a>,
+    pattern: &'p str,
+}
 
-    pub fn get_inode_table(&mut self){
-        for i in 0..self.num_inode_blocks(){
-            let mut buffer = [0; BLOCK_SIZE];
-            self.disk.read(i+2, &mut buffer);
-            for (j, value) in buffer.iter().enumerate() {
-                self.file_content_buffer[j + (i * BLOCK_SIZE)] = *value;
-            }
-        }
+impl<'a> Iterator for Find<'a, '_> {
+    type Item = DirEntry<'a>;
+
+    fn next(&mut self) -> Option<DirEntry<'a>> {
+        let pattern = self.pattern;
+        self.entries.find(|entry| glob_match(pattern, entry.name))
     }
+}
 
-    pub fn get_directory(&mut self) {
-        self.get_inode_table();
-        let mut dir_blocks = [0; MAX_FILE_BLOCKS];
-        let mut count = 0;
-        for block in self.file_content_buffer[2]..self.file_content_buffer[self.num_inode_bytes()] {
-            if dir_blocks.contains(&block){
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
 
-            } else{
-                dir_blocks[count] = block;
-                count += 1;
-            }
+impl FileType {
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => FileType::Directory,
+            2 => FileType::Symlink,
+            _ => FileType::File,
         }
+    }
 
-        for (i, block) in dir_blocks.iter().enumerate() {
-            if i == count {
-                break;
-            }
-            let mut buffer = [0; BLOCK_SIZE];
-            self.disk.read(*block as usize, &mut buffer);
-            for (j, value) in buffer.iter().enumerate(){
-                self.directory_buffer[j + (BLOCK_SIZE * i)] = *value;
-            }
+    fn to_byte(self) -> u8 {
+        match self {
+            FileType::File => 0,
+            FileType::Directory => 1,
+            FileType::Symlink => 2,
         }
-        
     }
+}
 
+/// An inode with room for `MAX_BLOCKS` block pointers. How many bytes those hold,
+/// inline or through blocks, depends on the geometry; see `capacity`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Inode<const MAX_BLOCKS: usize> {
+    bytes_stored: u16,
+    links: u8,
+    kind: FileType,
+    ctime: u32,
+    mtime: u32,
+    atime: u32,
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    xattr_block: u8,
+    /// Whether `blocks` holds the file's contents instead of pointers to them.
+    inline: bool,
+    blocks: [u8; MAX_BLOCKS],
+}
 
+impl<const MAX_BLOCKS: usize> Inode<MAX_BLOCKS> {
+    /// Size of an inode in the on-disk inode table.
+    const NUM_BYTES: usize = 23 + MAX_BLOCKS;
+    /// Set in the on-disk type byte of an inode storing its contents inline.
+    const INLINE_FLAG: u8 = 0x80;
 
-    pub fn open_append(&mut self, filename: &str) -> FileSystemResult<usize> {
-        self.get_directory();
+    fn new() -> Self {
+        Self {
+            bytes_stored: 0,
+            links: 0,
+            kind: FileType::File,
+            ctime: 0,
+            mtime: 0,
+            atime: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            xattr_block: 0,
+            inline: false,
+            blocks: [0; MAX_BLOCKS],
+        }
+    }
 
-        let mut namebuffer = ['\0'; MAX_FILENAME_BYTES];
-        for (i, c) in filename.chars().enumerate() {
-            namebuffer[i] = c 
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let time = |start: usize| u32::from_be_bytes(bytes[start..start + 4].try_into().unwrap());
+        let id = |start: usize| u16::from_be_bytes([bytes[start], bytes[start + 1]]);
+        // A `DynFileSystem` inode has room for more pointers than are stored.
+        let stored = MAX_BLOCKS.min(bytes.len() - 23);
+        let mut blocks = [0; MAX_BLOCKS];
+        blocks[..stored].copy_from_slice(&bytes[23..23 + stored]);
+        Self {
+            bytes_stored: u16::from_be_bytes([bytes[0], bytes[1]]),
+            links: bytes[2],
+            kind: FileType::from_byte(bytes[3] & !Self::INLINE_FLAG),
+            ctime: time(4),
+            mtime: time(8),
+            atime: time(12),
+            mode: id(16),
+            uid: id(18),
+            gid: id(20),
+            xattr_block: bytes[22],
+            inline: bytes[3] & Self::INLINE_FLAG != 0,
+            blocks,
         }
+    }
 
-        let mut name_spot = 0;
-        let mut char_spot = 0;
-        let mut name_flag = true;
-        let mut found_inode = false;
-        let mut ignore = false;
-        let mut count = 0;
-        for i in self.directory_buffer{
-            if ignore{
-                if count % MAX_FILENAME_BYTES == 0{
-                    ignore = false;
-                    name_flag = true;
-                    char_spot = 0;
-                    name_spot +=1;
+    fn to_bytes(self, bytes: &mut [u8]) {
+        bytes[0..2].copy_from_slice(&self.bytes_stored.to_be_bytes());
+        bytes[2] = self.links;
+        bytes[3] = self.kind.to_byte();
+        if self.inline {
+            bytes[3] |= Self::INLINE_FLAG;
+        }
+        bytes[4..8].copy_from_slice(&self.ctime.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.mtime.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.atime.to_be_bytes());
+        bytes[16..18].copy_from_slice(&self.mode.to_be_bytes());
+        bytes[18..20].copy_from_slice(&self.uid.to_be_bytes());
+        bytes[20..22].copy_from_slice(&self.gid.to_be_bytes());
+        bytes[22] = self.xattr_block;
+        let stored = MAX_BLOCKS.min(bytes.len() - 23);
+        bytes[23..23 + stored].copy_from_slice(&self.blocks[..stored]);
+    }
 
-                }
-            }
-            if !ignore{
-                if i as u8 as char != namebuffer[char_spot as usize% MAX_FILENAME_BYTES]{
-                    ignore = true;
+    /// The data block pointers, of which there are none while the contents are inline.
+    fn data_blocks(&self) -> &[u8] {
+        if self.inline {
+            &[]
+        } else {
+            &self.blocks
+        }
+    }
 
-                }
-                char_spot +=1;
-                if char_spot == MAX_FILENAME_BYTES && name_flag{
-                    name_spot +=1;
-                    found_inode = true;
-                    break;
-                }
-            }
-            count +=1;
+    /// How many bytes of contents the inode can describe in a file system of
+    /// `geometry`. Missing blocks are holes, so a file may be larger than the blocks
+    /// it has.
+    fn capacity(&self, geometry: Geometry) -> usize {
+        if self.inline {
+            geometry.inline_bytes()
+        } else {
+            geometry.max_file_bytes()
         }
+    }
 
-        
-        if found_inode{
-            if self.open_inodes[name_spot]{
-                return FileSystemResult::Err(FileSystemError::AlreadyOpen)
-            }
-            let inode_start = name_spot * self.num_inode_bytes();
-            let data = ((self.file_content_buffer[inode_start] as u16)<<8) | self.file_content_buffer[inode_start+1] as u16;
-            let mut inode_blocks = [0;MAX_FILE_BLOCKS];
-            let mut c = 0;
-            for i in inode_start + 2..inode_start+self.num_inode_bytes(){
-                if !(inode_blocks.contains(&self.file_content_buffer[i])){
-                    inode_blocks[c] = self.file_content_buffer[i];
-                    c +=1;
-                }
-            }
-            
-            let mut inode_for_file_entry = Inode 
-            { 
-                bytes_stored: data, 
-                blocks: inode_blocks
-            };
-            let mut new_buffer = [0;BLOCK_SIZE];
-            let mut c2 = 0;
-            for i in inode_blocks{
-                if i ==0{
-                    break;
-                }
-                else{
-                    c2 +=1;
-                }
-            }
-            let current:u8;
-            if c2 != 0{
-                self.disk.read(inode_for_file_entry.blocks[c2-1].into(), &mut new_buffer);
-                current = inode_for_file_entry.blocks[c2-1];
-            }
-            else{
-                self.disk.read(inode_for_file_entry.blocks[c2].into(), &mut new_buffer);
-                current = inode_for_file_entry.blocks[c2].into();
-            }
+    /// The blocks this inode holds a reference to: its data and attribute blocks.
+    fn pointers(&self) -> impl Iterator<Item = u8> + '_ {
+        self.data_blocks()
+            .iter()
+            .chain(core::iter::once(&self.xattr_block))
+            .copied()
+            .filter(|block| *block != 0)
+    }
+
+    /// Whether `caller` holds every permission bit in `access` (`MODE_READ`,
+    /// `MODE_WRITE`) through the owner, group or other class of `mode`.
+    fn permits(&self, caller: Credentials, access: u16) -> bool {
+        if caller.uid == Credentials::ROOT.uid {
+            return true;
+        }
+        let shift = if caller.uid == self.uid {
+            6
+        } else if caller.gid == self.gid {
+            3
+        } else {
+            0
+        };
+        (self.mode >> shift) & access == access
+    }
+}
 
+/// Records the geometry the disk was formatted with; see `Geometry::from_superblock`.
+/// Images from before it kept the bitmaps in blocks 0 and 1 and can't be mounted.
+const SUPERBLOCK: usize = 0;
+const INODE_FULL_BLOCK: usize = SUPERBLOCK + 1;
+const DATA_FULL_BLOCK: usize = INODE_FULL_BLOCK + 1;
+const INODE_TABLE_START: usize = DATA_FULL_BLOCK + 1;
+const DIRECTORY_INODE: usize = 0;
+const MODE_READ: u16 = 0o4;
+const MODE_WRITE: u16 = 0o2;
+const DEFAULT_FILE_MODE: u16 = 0o644;
+const DEFAULT_SYMLINK_MODE: u16 = 0o777;
+/// Set on a directory, only a file's owner (or root) may remove its names.
+const MODE_STICKY: u16 = 0o1000;
+/// Anyone may add names to the directory but only remove their own, as with `/tmp`.
+const DIRECTORY_MODE: u16 = MODE_STICKY | 0o777;
+/// Longest extended attribute name accepted by `set_xattr`.
+pub const MAX_XATTR_NAME_BYTES: usize = 16;
+/// Longest extended attribute value accepted by `set_xattr`.
+pub const MAX_XATTR_VALUE_BYTES: usize = 32;
+/// Bytes of a directory entry before its name: the name length and the inode number.
+const ENTRY_HEADER_BYTES: usize = 3;
+/// Symbolic links followed while resolving a name before giving up with `TooManySymlinks`.
+const MAX_SYMLINK_HOPS: usize = 8;
+/// Metadata blocks one transaction can stage. The journal region is a header block
+/// followed by this many copies. Writes, snapshots, fsck repairs and defragmenting
+/// commit in steps that fit; other updates fail with `TransactionTooBig` if they
+/// need more.
+pub const JOURNAL_CAPACITY: usize = 16;
+/// Most blocks one step of an operation done in steps stages, checksum-table blocks
+/// included: a block copied on write with the data bitmap, its reference count and
+/// the inode, say.
+const JOURNAL_STEP_BLOCKS: usize = 7;
+/// First byte of a journal header whose transaction was fully written and must be
+/// replayed if it wasn't checkpointed.
+const JOURNAL_COMMITTED: u8 = 1;
+/// Journal header bytes before the list of home block numbers: the commit byte, the
+/// block count and a checksum over both the list and the copies.
+const JOURNAL_HEADER_BYTES: usize = 6;
+/// Snapshots the snapshot table has room for.
+pub const MAX_SNAPSHOTS: usize = 8;
+/// Snapshot root bytes before the copy pointers: the name length and creation time.
+const SNAPSHOT_HEADER_BYTES: usize = 5;
+/// Snapshot table byte after the slots holding the root of a snapshot being taken,
+/// or 0. The two bytes after it count the block pointers shared with it so far.
+const SNAPSHOT_PENDING: usize = 1 + MAX_SNAPSHOTS;
+/// Snapshot table byte holding the root of a snapshot being deleted, or 0. The two
+/// bytes after it count the block pointers it has released so far.
+const SNAPSHOT_DROPPING: usize = SNAPSHOT_PENDING + 3;
+/// Starts the superblock, ahead of the geometry fields. Any block holds the 16 bytes,
+/// as the journal header is bigger.
+const SUPERBLOCK_MAGIC: [u8; 4] = *b"RFS1";
+const SUPERBLOCK_BYTES: usize = 16;
 
-            let mut offset = 0;
-            for (i, value) in new_buffer.iter().enumerate() {
-                if *value == (0 as u8){
-                    offset = i;
-                    break;
-                }
+/// The parameters a `FileSystem` is instantiated with, and the layout they give it.
+/// Everything is a `const fn`, so the other parameters can be worked out where the
+/// type is named, e.g. `MAX_FILE_BYTES` as
+/// `{ Geometry::new(64, 255, 8, 32, 8).max_file_bytes() }`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Geometry {
+    pub block_size: usize,
+    pub num_blocks: usize,
+    pub max_file_blocks: usize,
+    pub max_files_stored: usize,
+    pub max_filename_bytes: usize,
+}
+
+impl Geometry {
+    pub const fn new(
+        block_size: usize,
+        num_blocks: usize,
+        max_file_blocks: usize,
+        max_files_stored: usize,
+        max_filename_bytes: usize,
+    ) -> Self {
+        Self {
+            block_size,
+            num_blocks,
+            max_file_blocks,
+            max_files_stored,
+            max_filename_bytes,
+        }
+    }
+
+    /// The `MAX_FILE_BYTES` that goes with the other parameters.
+    pub const fn max_file_bytes(&self) -> usize {
+        self.max_file_blocks * self.block_size
+    }
+
+    /// Largest number of bytes a single file may hold. The final byte of the block
+    /// range stays unused, as it did when files were NUL-terminated.
+    pub const fn file_capacity(&self) -> usize {
+        self.max_file_bytes() - 1
+    }
+
+    /// Most bytes of contents an inode can hold in place of its block pointers.
+    pub const fn inline_bytes(&self) -> usize {
+        if self.max_file_blocks < self.block_size {
+            self.max_file_blocks
+        } else {
+            self.block_size
+        }
+    }
+
+    /// Size of an inode: its fixed fields, then one pointer per file block.
+    pub const fn inode_bytes(&self) -> usize {
+        23 + self.max_file_blocks
+    }
+
+    pub const fn inodes_per_block(&self) -> usize {
+        self.block_size / self.inode_bytes()
+    }
+
+    pub const fn inode_blocks(&self) -> usize {
+        self.max_files_stored.div_ceil(self.inodes_per_block())
+    }
+
+    /// The journal header block plus room for one transaction's staged blocks.
+    pub const fn journal_blocks(&self) -> usize {
+        1 + JOURNAL_CAPACITY
+    }
+
+    /// Blocks holding the checksum table: one big-endian CRC32 per block on the disk.
+    pub const fn checksum_blocks(&self) -> usize {
+        (self.num_blocks * 4).div_ceil(self.block_size)
+    }
+
+    /// Blocks holding the reference-count table: one byte per block on the disk.
+    pub const fn refcount_blocks(&self) -> usize {
+        self.num_blocks.div_ceil(self.block_size)
+    }
+
+    /// The journal region sits between the inode table and the data blocks.
+    const fn journal_start(&self) -> usize {
+        INODE_TABLE_START + self.inode_blocks()
+    }
+
+    /// The checksum table follows the journal.
+    const fn checksum_start(&self) -> usize {
+        self.journal_start() + self.journal_blocks()
+    }
+
+    /// The reference-count table follows the checksum table.
+    const fn refcount_start(&self) -> usize {
+        self.checksum_start() + self.checksum_blocks()
+    }
+
+    /// The single block listing snapshots, just before the data blocks.
+    const fn snapshot_table_block(&self) -> usize {
+        self.refcount_start() + self.refcount_blocks()
+    }
+
+    pub const fn first_data_block(&self) -> usize {
+        self.snapshot_table_block() + 1
+    }
+
+    /// The inode table block holding inode `inode_num`, and where in it the inode starts.
+    const fn inode_location(&self, inode_num: usize) -> (usize, usize) {
+        (
+            INODE_TABLE_START + inode_num / self.inodes_per_block(),
+            (inode_num % self.inodes_per_block()) * self.inode_bytes(),
+        )
+    }
+
+    /// The checksum table block holding the checksum of `block`, and its offset there.
+    const fn checksum_location(&self, block: usize) -> (usize, usize) {
+        let offset = block * 4;
+        (
+            self.checksum_start() + offset / self.block_size,
+            offset % self.block_size,
+        )
+    }
+
+    /// The reference-count table block holding the count of `block`, and its offset
+    /// there.
+    const fn refcount_location(&self, block: u8) -> (usize, usize) {
+        let block = block as usize;
+        (
+            self.refcount_start() + block / self.block_size,
+            block % self.block_size,
+        )
+    }
+
+    pub const fn data_blocks(&self) -> usize {
+        self.num_blocks.saturating_sub(self.first_data_block())
+    }
+
+    /// The most files the bitmaps, inode numbers and directory allow, for the largest
+    /// `max_files_stored` worth trying. Whether their inode table fits on the disk is
+    /// up to `check`.
+    pub const fn max_files_limit(&self) -> usize {
+        let mut limit = self.block_size * 8;
+        let directory = self.max_file_bytes() / (ENTRY_HEADER_BYTES + self.max_filename_bytes);
+        if directory < limit {
+            limit = directory;
+        }
+        if (u16::MAX as usize) < limit {
+            limit = u16::MAX as usize;
+        }
+        limit
+    }
+
+    /// Reads the geometry recorded at the start of a superblock, if there is one. Only
+    /// the first 16 bytes are needed, so it works before the block size is known.
+    pub fn from_superblock(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < SUPERBLOCK_BYTES || bytes[..4] != SUPERBLOCK_MAGIC {
+            return None;
+        }
+        let field = |start: usize| u16::from_be_bytes([bytes[start], bytes[start + 1]]) as usize;
+        Some(Self::new(
+            u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize,
+            field(8),
+            field(10),
+            field(12),
+            field(14),
+        ))
+    }
+
+    fn write_superblock(&self, bytes: &mut [u8]) {
+        bytes[..4].copy_from_slice(&SUPERBLOCK_MAGIC);
+        bytes[4..8].copy_from_slice(&(self.block_size as u32).to_be_bytes());
+        let fields = [
+            self.num_blocks,
+            self.max_file_blocks,
+            self.max_files_stored,
+            self.max_filename_bytes,
+        ];
+        for (i, field) in fields.into_iter().enumerate() {
+            bytes[8 + 2 * i..10 + 2 * i].copy_from_slice(&(field as u16).to_be_bytes());
+        }
+    }
+
+    /// Checks that a file system can be laid out with these parameters, describing
+    /// the first problem found.
+    pub const fn check(&self) -> Result<(), &'static str> {
+        if !self.block_size.is_multiple_of(4)
+            || self.block_size < JOURNAL_HEADER_BYTES + JOURNAL_CAPACITY
+        {
+            return Err("BLOCK_SIZE must be a multiple of 4 that holds the journal header");
+        }
+        if self.num_blocks > u8::MAX as usize {
+            return Err("NUM_BLOCKS must fit in a u8 block pointer");
+        }
+        if self.max_file_bytes() > u16::MAX as usize {
+            return Err("MAX_FILE_BLOCKS * BLOCK_SIZE must fit in a u16 file size");
+        }
+        if self.max_filename_bytes > u8::MAX as usize {
+            return Err("MAX_FILENAME_BYTES must fit in a u8 name length");
+        }
+        if self.inodes_per_block() == 0 {
+            return Err("an inode must fit in a block");
+        }
+        if self.max_files_stored > self.max_files_limit() {
+            return Err("MAX_FILES_STORED must fit the inode bitmap and the directory");
+        }
+        if self.inode_blocks() * 2 >= self.num_blocks
+            || self.inodes_per_block() * self.inode_blocks() * self.inode_bytes()
+                > u16::MAX as usize
+        {
+            return Err("the inode table must fit well within the disk");
+        }
+        if self.first_data_block() >= self.num_blocks || self.data_blocks() > self.block_size * 8 {
+            return Err("the data blocks must fit the disk and the data bitmap");
+        }
+        if SNAPSHOT_DROPPING + 3 > self.block_size
+            || SNAPSHOT_HEADER_BYTES + 1 + self.inode_blocks() + self.max_filename_bytes
+                > self.block_size
+        {
+            return Err("the snapshot table and a snapshot root must fit in a block");
+        }
+        Ok(())
+    }
+}
+
+/// Everything `FileSystem` and `DynFileSystem` do the same way: allocation, reference
+/// counts, inodes, the directory and name lookup. The two keep their blocks in
+/// different places, one staged through a journal on a block device and the other in
+/// an image in memory, so each supplies block access and a few hooks, and the rest is
+/// worked out here from the geometry. `MAX_BLOCKS` is the pointer room of their inodes.
+trait Layout<const MAX_BLOCKS: usize> {
+    type Buffer: AsRef<[u8]> + AsMut<[u8]>;
+    type Index: AsRef<[u16]> + AsMut<[u16]>;
+
+    fn geometry(&self) -> Geometry;
+
+    fn now(&mut self) -> u32;
+
+    fn directory(&self) -> &Directory<Self::Buffer, Self::Index>;
+
+    fn directory_mut(&mut self) -> &mut Directory<Self::Buffer, Self::Index>;
+
+    /// Runs `read` on the current contents of metadata block `block`, once they've been
+    /// checked against the block's checksum.
+    fn read_meta_with<T: Copy>(
+        &mut self,
+        block: usize,
+        read: impl FnOnce(&[u8]) -> T,
+    ) -> FileSystemResult<T>;
+
+    /// Changes metadata block `block` through `change`, once it's been checked.
+    fn modify_meta(&mut self, block: usize, change: impl FnOnce(&mut [u8]))
+        -> FileSystemResult<()>;
+
+    /// Runs `read` on file data block `block`, once it's been checked.
+    fn read_data_with<T: Copy>(
+        &mut self,
+        block: usize,
+        read: impl FnOnce(&[u8]) -> T,
+    ) -> FileSystemResult<T>;
+
+    /// Fills directory block `index` of the directory buffer from metadata block
+    /// `block`, or with zeros if `block` is 0.
+    fn load_directory_block(&mut self, index: usize, block: u8) -> FileSystemResult<()>;
+
+    /// Writes directory block `index` of the directory buffer to metadata block `block`.
+    fn save_directory_block(&mut self, index: usize, block: u8);
+
+    /// Called once data block `block` has been taken from the free bitmap.
+    fn block_allocated(&mut self, _block: u8) {}
+
+    /// Called once data block `block` has been returned to the free bitmap.
+    fn block_freed(&mut self, _block: u8) {}
+
+    /// Called once `inode` has been written to the inode table as `inode_num`.
+    fn inode_written(&mut self, _inode_num: usize, _inode: &Inode<MAX_BLOCKS>) {}
+
+    /// Whether a descriptor still has `inode_num` open, keeping it alive without names.
+    fn is_open(&self, _inode_num: usize) -> bool {
+        false
+    }
+
+    /// Reads an inode as it currently stands, including changes not yet written back.
+    fn current_inode(&mut self, inode_num: usize) -> FileSystemResult<Inode<MAX_BLOCKS>> {
+        self.read_inode(inode_num)
+    }
+
+    /// Writes what an empty file system starts with: the superblock, bitmaps marking
+    /// the metadata blocks and the directory's inode as used, and that inode.
+    fn write_empty_layout(&mut self) -> FileSystemResult<()> {
+        let geometry = self.geometry();
+        fs_try!(self.modify_meta(SUPERBLOCK, |block| geometry.write_superblock(block)));
+        fs_try!(self.set_bit(INODE_FULL_BLOCK, DIRECTORY_INODE, true));
+        fs_try!(self.modify_meta(DATA_FULL_BLOCK, |bitmap| {
+            for block in 0..geometry.first_data_block() {
+                set_bit_to(bitmap, block, true);
             }
-            let file_table_entry: FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE> = FileInfo{
-            inode: inode_for_file_entry,
-            inode_num: name_spot,
-            current_block: current.into(),
-            offset: offset,
-            writing: true,
-            reading: false,
-            block_buffer: new_buffer
-            };
-            self.open_inodes[name_spot] = true;
-            let mut fd = 0;
-            for i in self.open{
-                if i.is_none() {
-                    self.open[fd] = Some(file_table_entry);
-                    break;
-                }
-                fd += 1;
+        }));
+        let mut dir_inode = Inode::new();
+        dir_inode.links = 1;
+        dir_inode.kind = FileType::Directory;
+        dir_inode.mode = DIRECTORY_MODE;
+        dir_inode.ctime = self.now();
+        dir_inode.mtime = dir_inode.ctime;
+        dir_inode.atime = dir_inode.ctime;
+        self.write_inode(DIRECTORY_INODE, &dir_inode)
+    }
+
+    fn find_free_bit(
+        &mut self,
+        bitmap_block: usize,
+        start: usize,
+        end: usize,
+    ) -> FileSystemResult<Option<usize>> {
+        self.read_meta_with(bitmap_block, |bitmap| first_clear_bit(bitmap, start, end))
+    }
+
+    fn count_free_bits(
+        &mut self,
+        bitmap_block: usize,
+        start: usize,
+        end: usize,
+    ) -> FileSystemResult<usize> {
+        self.read_meta_with(bitmap_block, |bitmap| {
+            (start..end).filter(|i| !bit_is_set(bitmap, *i)).count()
+        })
+    }
+
+    fn set_bit(&mut self, bitmap_block: usize, index: usize, used: bool) -> FileSystemResult<()> {
+        self.modify_meta(bitmap_block, |bitmap| set_bit_to(bitmap, index, used))
+    }
+
+    fn num_free_blocks(&mut self) -> FileSystemResult<usize> {
+        let geometry = self.geometry();
+        self.count_free_bits(
+            DATA_FULL_BLOCK,
+            geometry.first_data_block(),
+            geometry.num_blocks,
+        )
+    }
+
+    fn alloc_block(&mut self) -> FileSystemResult<u8> {
+        let geometry = self.geometry();
+        let free = fs_try!(self.find_free_bit(
+            DATA_FULL_BLOCK,
+            geometry.first_data_block(),
+            geometry.num_blocks
+        ));
+        match free {
+            Some(block) => {
+                fs_try!(self.set_bit(DATA_FULL_BLOCK, block, true));
+                self.block_allocated(block as u8);
+                FileSystemResult::Ok(block as u8)
             }
-            return FileSystemResult::Ok(fd)
+            None => FileSystemResult::Err(FileSystemError::DiskFull),
+        }
+    }
 
+    fn free_block(&mut self, block: u8) -> FileSystemResult<()> {
+        fs_try!(self.set_bit(DATA_FULL_BLOCK, block as usize, false));
+        self.block_freed(block);
+        FileSystemResult::Ok(())
+    }
+
+    // Reflinked files share data blocks. The reference-count table holds how many
+    // files point at each shared block, and 0 for every block with at most one owner,
+    // so files that are never reflinked don't touch it.
+
+    /// Number of files pointing at data block `block`, counting a lone owner as 1.
+    fn block_refs(&mut self, block: u8) -> FileSystemResult<u8> {
+        let (table_block, start) = self.geometry().refcount_location(block);
+        self.read_meta_with(table_block, |table| table[start].max(1))
+    }
+
+    fn set_block_refs(&mut self, block: u8, refs: u8) -> FileSystemResult<()> {
+        let (table_block, start) = self.geometry().refcount_location(block);
+        let stored = if refs > 1 { refs } else { 0 };
+        if fs_try!(self.read_meta_with(table_block, |table| table[start])) == stored {
+            return FileSystemResult::Ok(());
+        }
+        self.modify_meta(table_block, |table| table[start] = stored)
+    }
 
+    /// Gives up one file's claim on data block `block`, freeing it with the last.
+    fn release_block(&mut self, block: u8) -> FileSystemResult<()> {
+        match fs_try!(self.block_refs(block)) {
+            1 => self.free_block(block),
+            refs => self.set_block_refs(block, refs - 1),
         }
-        else{
-            //println!("here2");
-            return FileSystemResult::Err(FileSystemError::FileNotFound);
+    }
+
+    fn alloc_inode(&mut self) -> FileSystemResult<usize> {
+        let max_files = self.geometry().max_files_stored;
+        match fs_try!(self.find_free_bit(INODE_FULL_BLOCK, 0, max_files)) {
+            Some(inode_num) => {
+                fs_try!(self.set_bit(INODE_FULL_BLOCK, inode_num, true));
+                FileSystemResult::Ok(inode_num)
+            }
+            None => FileSystemResult::Err(FileSystemError::TooManyFiles),
         }
     }
 
-    
+    fn read_inode(&mut self, inode_num: usize) -> FileSystemResult<Inode<MAX_BLOCKS>> {
+        let geometry = self.geometry();
+        let (block, start) = geometry.inode_location(inode_num);
+        let end = start + geometry.inode_bytes();
+        self.read_meta_with(block, |table| Inode::from_bytes(&table[start..end]))
+    }
+
+    fn write_inode(&mut self, inode_num: usize, inode: &Inode<MAX_BLOCKS>) -> FileSystemResult<()> {
+        let geometry = self.geometry();
+        let (block, start) = geometry.inode_location(inode_num);
+        let end = start + geometry.inode_bytes();
+        fs_try!(self.modify_meta(block, |table| inode.to_bytes(&mut table[start..end])));
+        self.inode_written(inode_num, inode);
+        FileSystemResult::Ok(())
+    }
 
-    pub fn read(&mut self, fd: usize, buffer: &mut [u8]) -> FileSystemResult<usize> {
-        let mut pre_file_to_read_from = self.open[fd];
-        if pre_file_to_read_from.is_none(){
-            return FileSystemResult::Err(FileSystemError::FileNotOpen)
+    /// Frees every block owned by `inode_num` along with the inode itself.
+    fn release_inode(&mut self, inode_num: usize) -> FileSystemResult<()> {
+        let inode = fs_try!(self.read_inode(inode_num));
+        for block in inode.pointers() {
+            fs_try!(self.release_block(block));
+        }
+        fs_try!(self.write_inode(inode_num, &Inode::new()));
+        self.set_bit(INODE_FULL_BLOCK, inode_num, false)
+    }
+
+    /// Reads up to `buffer.len()` bytes of `inode`'s contents from its start, with
+    /// holes read as zeros, and returns how many there were.
+    fn read_inode_data(
+        &mut self,
+        inode: &Inode<MAX_BLOCKS>,
+        buffer: &mut [u8],
+    ) -> FileSystemResult<usize> {
+        let geometry = self.geometry();
+        let len = buffer.len().min(inode.bytes_stored as usize);
+        if inode.inline {
+            let len = len.min(geometry.inline_bytes());
+            buffer[..len].copy_from_slice(&inode.blocks[..len]);
+            return FileSystemResult::Ok(len);
         }
-        let mut file_to_read_from = pre_file_to_read_from.unwrap();
-        if file_to_read_from.writing{
-            return FileSystemResult::Err(FileSystemError::NotOpenForRead)
+        for (i, chunk) in buffer[..len].chunks_mut(geometry.block_size).enumerate() {
+            match inode.blocks[i] {
+                0 => chunk.fill(0),
+                block => fs_try!(self.read_data_with(block as usize, |data| {
+                    chunk.copy_from_slice(&data[..chunk.len()])
+                })),
+            }
         }
-        
-        let mut user_buffer_count = 0;
-        let mut cur_buf = [0 as u8;BLOCK_SIZE];
-        let mut bytes_read = 0;
-        // Probably have to check for data stored in inode 
-        let inode_start = file_to_read_from.inode_num * self.num_inode_bytes() +2;
-        let inode_stop = file_to_read_from.inode_num * self.num_inode_bytes() + self.num_inode_bytes();
-        let mut all_inode_blocks = &self.file_content_buffer[inode_start..inode_stop];
-        let mut unique_blocks = [0 as usize; MAX_FILE_BLOCKS];
-        let mut count = 1;
-        let mut block = file_to_read_from.current_block;
-        let mut used_blocks = 0;
-        for i in all_inode_blocks{
-            if !(unique_blocks.contains(&(*i as usize))){
-                unique_blocks[count] = *i as usize;
-                if block == *i as usize{
-                    used_blocks = count
+        FileSystemResult::Ok(len)
+    }
+
+    /// Loads the directory into the directory buffer, if it isn't already, and returns
+    /// its size in bytes. Once loaded, the copy and its index are kept in step with
+    /// every change, so later calls don't touch the disk.
+    fn load_directory(&mut self) -> FileSystemResult<usize> {
+        if let Some(size) = self.directory().len {
+            return FileSystemResult::Ok(size);
+        }
+        let geometry = self.geometry();
+        let dir_inode = fs_try!(self.read_inode(DIRECTORY_INODE));
+        for (i, block) in dir_inode.blocks[..geometry.max_file_blocks]
+            .iter()
+            .enumerate()
+        {
+            fs_try!(self.load_directory_block(i, *block));
+        }
+        let size = (dir_inode.bytes_stored as usize).min(geometry.max_file_bytes());
+        let directory = self.directory_mut();
+        directory.len = Some(size);
+        directory.rebuild_index();
+        FileSystemResult::Ok(size)
+    }
+
+    /// Writes the directory bytes from `start` to its end back to disk, allocating
+    /// blocks as it grows. Blocks past the end stay with the directory for reuse.
+    fn save_directory(&mut self, start: usize, size: usize) -> FileSystemResult<()> {
+        let block_size = self.geometry().block_size;
+        let original = fs_try!(self.read_inode(DIRECTORY_INODE));
+        let mut dir_inode = original;
+        let end = size.max(self.directory().size()).div_ceil(block_size);
+        for i in start / block_size..end {
+            let block = dir_inode.blocks[i];
+            if block == 0 || fs_try!(self.block_refs(block)) > 1 {
+                dir_inode.blocks[i] = fs_try!(self.alloc_block());
+                if block != 0 {
+                    fs_try!(self.release_block(block));
                 }
-                count +=1;
             }
-            if count == MAX_FILE_BLOCKS{
-                break;
+            self.save_directory_block(i, dir_inode.blocks[i]);
+        }
+        dir_inode.bytes_stored = size as u16;
+        self.directory_mut().len = Some(size);
+        if dir_inode != original {
+            fs_try!(self.write_inode(DIRECTORY_INODE, &dir_inode));
+        }
+        FileSystemResult::Ok(())
+    }
+
+    /// Blocks `save_directory` must allocate to rewrite directory bytes `start..end`:
+    /// one for each block not yet allocated or shared with a snapshot.
+    fn directory_blocks_needed(&mut self, start: usize, end: usize) -> FileSystemResult<usize> {
+        let block_size = self.geometry().block_size;
+        let dir_inode = fs_try!(self.read_inode(DIRECTORY_INODE));
+        let mut needed = 0;
+        for block in &dir_inode.blocks[start / block_size..end.div_ceil(block_size)] {
+            if *block == 0 || fs_try!(self.block_refs(*block)) > 1 {
+                needed += 1;
             }
         }
-        
+        FileSystemResult::Ok(needed)
+    }
+
+    /// Checks that a new entry for a `name_len`-byte name fits in the loaded
+    /// directory, and returns how many blocks saving it will allocate.
+    fn check_entry_room(&mut self, name_len: usize) -> FileSystemResult<usize> {
+        let geometry = self.geometry();
+        let size = self.directory().size();
+        let end = size + ENTRY_HEADER_BYTES + name_len;
+        if self.directory().entries().count() >= geometry.max_files_stored
+            || end > geometry.max_file_bytes()
+        {
+            return FileSystemResult::Err(FileSystemError::TooManyFiles);
+        }
+        self.directory_blocks_needed(size, end)
+    }
+
+    /// Appends `filename -> inode_num` to the loaded directory.
+    fn add_entry(&mut self, filename: &[u8], inode_num: usize) -> FileSystemResult<()> {
+        if fs_try!(self.check_entry_room(filename.len())) > fs_try!(self.num_free_blocks()) {
+            return FileSystemResult::Err(FileSystemError::DiskFull);
+        }
+        let offset = self.directory().size();
+        let end = push_entry(
+            self.directory_mut().buffer.as_mut(),
+            offset,
+            filename,
+            inode_num,
+        );
+        fs_try!(self.save_directory(offset, end));
+        self.directory_mut().index_insert(offset);
+        FileSystemResult::Ok(())
+    }
+
+    /// Removes the entry at `offset` from the loaded directory, moving later entries
+    /// down over it.
+    fn remove_entry(&mut self, offset: usize) -> FileSystemResult<()> {
+        let size = self.directory().size();
+        if fs_try!(self.directory_blocks_needed(offset, size)) > fs_try!(self.num_free_blocks()) {
+            return FileSystemResult::Err(FileSystemError::DiskFull);
+        }
+        let end = pull_entry(self.directory_mut().buffer.as_mut(), size, offset);
+        fs_try!(self.save_directory(offset, end));
+        self.directory_mut().rebuild_index();
+        FileSystemResult::Ok(())
+    }
+
+    /// Removes the name at `offset` in the loaded directory. The file's blocks are
+    /// freed with its last name, unless a descriptor still has it open.
+    fn remove_name(&mut self, offset: usize) -> FileSystemResult<()> {
+        let inode_num = self.directory().entry_inode(offset);
+        fs_try!(self.remove_entry(offset));
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        inode.links = inode.links.saturating_sub(1);
+        fs_try!(self.write_inode(inode_num, &inode));
+        if inode.links == 0 && !self.is_open(inode_num) {
+            fs_try!(self.release_inode(inode_num));
+        }
+        FileSystemResult::Ok(())
+    }
+
+    /// Allocates an inode of type `kind` with `num_blocks` data blocks and enters it in
+    /// the directory under `filename`. Nothing is allocated unless everything fits.
+    /// Without blocks, the inode starts out storing its contents inline.
+    fn create_inode(
+        &mut self,
+        filename: &[u8],
+        kind: FileType,
+        owner: Credentials,
+        num_blocks: usize,
+    ) -> FileSystemResult<usize> {
+        fs_try!(self.load_directory());
+        let entry_blocks = fs_try!(self.check_entry_room(filename.len()));
+        if fs_try!(self.num_free_blocks()) < num_blocks + entry_blocks {
+            return FileSystemResult::Err(FileSystemError::DiskFull);
+        }
+        let inode_num = fs_try!(self.alloc_inode());
+        let mut inode = Inode::new();
+        inode.links = 1;
+        inode.kind = kind;
+        inode.mode = match kind {
+            FileType::Symlink => DEFAULT_SYMLINK_MODE,
+            _ => DEFAULT_FILE_MODE,
+        };
+        inode.uid = owner.uid;
+        inode.gid = owner.gid;
+        inode.ctime = self.now();
+        inode.mtime = inode.ctime;
+        inode.atime = inode.ctime;
+        inode.inline = num_blocks == 0;
+        for block in inode.blocks.iter_mut().take(num_blocks) {
+            *block = fs_try!(self.alloc_block());
+        }
+        fs_try!(self.write_inode(inode_num, &inode));
+        fs_try!(self.add_entry(filename, inode_num));
+        FileSystemResult::Ok(inode_num)
+    }
 
-        for i in 0..buffer.len(){
-            if count == used_blocks{
-                break;
+    /// Follows symbolic links starting from `filename`. The name the chain ends at is
+    /// copied into `name`, which holds the longest name allowed; returns its length
+    /// and its inode, if that name exists.
+    fn resolve(
+        &mut self,
+        filename: &str,
+        name: &mut [u8],
+    ) -> FileSystemResult<(usize, Option<usize>)> {
+        fs_try!(check_filename(filename, self.geometry().max_filename_bytes));
+        let mut len = filename.len();
+        name[..len].copy_from_slice(filename.as_bytes());
+        for _ in 0..=MAX_SYMLINK_HOPS {
+            fs_try!(self.load_directory());
+            let inode_num = match self.directory().find_entry(&name[..len]) {
+                Some(offset) => self.directory().entry_inode(offset),
+                None => return FileSystemResult::Ok((len, None)),
+            };
+            let inode = fs_try!(self.read_inode(inode_num));
+            if inode.kind != FileType::Symlink {
+                return FileSystemResult::Ok((len, Some(inode_num)));
             }
-            let mut disk_buffer = [0;BLOCK_SIZE];
-            self.disk.read(block as usize, &mut disk_buffer);
-            if disk_buffer[file_to_read_from.offset] == 0{
-                break;
+            len = fs_try!(self.read_inode_data(&inode, name));
+        }
+        FileSystemResult::Err(FileSystemError::TooManySymlinks)
+    }
+
+    /// Resolves `filename` to the inode of an existing, non-symlink file.
+    fn lookup(&mut self, filename: &str) -> FileSystemResult<usize> {
+        // `Geometry::check` keeps name lengths within a byte.
+        let mut name = [0; u8::MAX as usize];
+        let max_len = self.geometry().max_filename_bytes;
+        match fs_try!(self.resolve(filename, &mut name[..max_len])) {
+            (_, Some(inode_num)) => FileSystemResult::Ok(inode_num),
+            (_, None) => FileSystemResult::Err(FileSystemError::FileNotFound),
+        }
+    }
+
+    /// Describes the file `filename` names, following symbolic links.
+    fn stat_file(&mut self, filename: &str) -> FileSystemResult<Stat> {
+        let inode_num = fs_try!(self.lookup(filename));
+        let inode = fs_try!(self.current_inode(inode_num));
+        FileSystemResult::Ok(Stat {
+            inode: inode_num,
+            file_type: inode.kind,
+            size: inode.bytes_stored as usize,
+            blocks: inode
+                .data_blocks()
+                .iter()
+                .filter(|block| **block != 0)
+                .count(),
+            links: inode.links,
+            mode: inode.mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            ctime: inode.ctime,
+            mtime: inode.mtime,
+            atime: inode.atime,
+        })
+    }
+
+    /// Fills `listed`, indexed by inode number, with the type and size of each inode
+    /// the directory names, for a `ReadDir` over the directory to look up.
+    fn list_inodes(&mut self, listed: &mut [Option<(FileType, usize)>]) -> FileSystemResult<()> {
+        fs_try!(self.load_directory());
+        listed.fill(None);
+        let mut offset = 0;
+        while self.directory().entry_fits(offset) {
+            let inode_num = self.directory().entry_inode(offset);
+            offset += self.directory().entry_len(offset);
+            if inode_num == DIRECTORY_INODE
+                || inode_num >= listed.len()
+                || listed[inode_num].is_some()
+            {
+                continue;
             }
-            if file_to_read_from.offset == BLOCK_SIZE -1{
-                if count == used_blocks{
-                    break;
+            let inode = fs_try!(self.current_inode(inode_num));
+            listed[inode_num] = Some((inode.kind, inode.bytes_stored as usize));
+        }
+        FileSystemResult::Ok(())
+    }
+}
+
+#[derive(core::fmt::Debug)]
+pub struct FileSystem<
+    const MAX_OPEN: usize,
+    const BLOCK_SIZE: usize,
+    const NUM_BLOCKS: usize,
+    const MAX_FILE_BLOCKS: usize,
+    const MAX_FILE_BYTES: usize,
+    const MAX_FILES_STORED: usize,
+    const MAX_FILENAME_BYTES: usize,
+    C: Clock = CounterClock,
+    D: BlockDevice<BLOCK_SIZE> = ramdisk::RamDisk<BLOCK_SIZE, NUM_BLOCKS>,
+> {
+    open: [Option<FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>>; MAX_OPEN],
+    disk: D,
+    directory: Directory<[u8; MAX_FILE_BYTES], [u16; MAX_FILE_BYTES]>,
+    open_inodes: [bool; MAX_FILES_STORED],
+    /// Type and size of each inode the directory names, filled in by `read_dir` for
+    /// its iterator to look up.
+    listed_inodes: [Option<(FileType, usize)>; MAX_FILES_STORED],
+    clock: C,
+    /// Metadata blocks written by the current transaction, not yet on disk.
+    journal_blocks: [[u8; BLOCK_SIZE]; JOURNAL_CAPACITY],
+    /// Home block number of each entry in `journal_blocks`.
+    journal_targets: [u8; JOURNAL_CAPACITY],
+    journal_len: usize,
+    /// Set once a block didn't fit in the journal, so the transaction can't commit.
+    journal_overflowed: bool,
+    /// Set while fsck repairs, whose transaction may be committed in pieces.
+    split_transactions: bool,
+    /// Data blocks freed by the current transaction, discarded once it commits.
+    freed: [bool; NUM_BLOCKS],
+    /// Root block of the snapshot mounted in place of the live file system, if any.
+    snapshot_view: Option<u8>,
+    /// Set when mounted read-only: nothing is ever written to the disk.
+    mounted_read_only: bool,
+    /// Why the disk can't be used, found on mounting; every operation fails with it.
+    mount_error: Option<FileSystemError>,
+}
+//<16, 64, 255, 8, 512, 32, 8>
+impl<
+        const MAX_OPEN: usize,
+        const BLOCK_SIZE: usize,
+        const NUM_BLOCKS: usize,
+        const MAX_FILE_BLOCKS: usize,
+        const MAX_FILE_BYTES: usize,
+        const MAX_FILES_STORED: usize,
+        const MAX_FILENAME_BYTES: usize,
+        C: Clock,
+        D: BlockDevice<BLOCK_SIZE>,
+    >
+    FileSystem<
+        MAX_OPEN,
+        BLOCK_SIZE,
+        NUM_BLOCKS,
+        MAX_FILE_BLOCKS,
+        MAX_FILE_BYTES,
+        MAX_FILES_STORED,
+        MAX_FILENAME_BYTES,
+        C,
+        D,
+    >
+{
+    pub fn new(disk: D) -> Self
+    where
+        C: Default,
+    {
+        Self::with_clock(disk, C::default())
+    }
+
+    pub fn with_clock(disk: D, clock: C) -> Self {
+        Self::mount(disk, clock, false)
+    }
+
+    /// Mounts `disk` without ever writing to it, so one image can be shared safely.
+    /// Anything that would change the file system fails with `ReadOnly`.
+    pub fn new_read_only(disk: D) -> Self
+    where
+        C: Default,
+    {
+        Self::mount(disk, C::default(), true)
+    }
+
+    pub fn with_clock_read_only(disk: D, clock: C) -> Self {
+        Self::mount(disk, clock, true)
+    }
+
+    const GEOMETRY: Geometry = Geometry::new(
+        BLOCK_SIZE,
+        NUM_BLOCKS,
+        MAX_FILE_BLOCKS,
+        MAX_FILES_STORED,
+        MAX_FILENAME_BYTES,
+    );
+
+    /// Evaluated by `mount`, so a geometry that can't work fails to compile.
+    const VALID_GEOMETRY: () = {
+        if MAX_FILE_BYTES != Self::GEOMETRY.max_file_bytes() {
+            panic!("MAX_FILE_BYTES must be MAX_FILE_BLOCKS * BLOCK_SIZE");
+        }
+        if let Err(problem) = Self::GEOMETRY.check() {
+            panic!("{}", problem);
+        }
+    };
+
+    fn mount(disk: D, clock: C, read_only: bool) -> Self {
+        let () = Self::VALID_GEOMETRY;
+        let mut result = Self {
+            open: [None; MAX_OPEN],
+            disk,
+            open_inodes: [false; MAX_FILES_STORED],
+            listed_inodes: [None; MAX_FILES_STORED],
+            directory: Directory {
+                buffer: [0; MAX_FILE_BYTES],
+                len: None,
+                index: [0; MAX_FILE_BYTES],
+            },
+            clock,
+            journal_blocks: [[0; BLOCK_SIZE]; JOURNAL_CAPACITY],
+            journal_targets: [0; JOURNAL_CAPACITY],
+            journal_len: 0,
+            journal_overflowed: false,
+            split_transactions: false,
+            freed: [false; NUM_BLOCKS],
+            snapshot_view: None,
+            mounted_read_only: read_only,
+            mount_error: None,
+        };
+        result.mount_error = result.check_format();
+        if result.mount_error.is_some() {
+            return result;
+        }
+        result.replay_journal();
+        if read_only {
+            // A rollback under way can't be finished, but it ends up with the
+            // snapshot's contents, so that's what is shown.
+            if let FileSystemResult::Ok(table) = result.read_snapshot_table() {
+                if table[0] != 0 {
+                    result.snapshot_view = Some(table[table[0] as usize]);
                 }
-                buffer[i] = disk_buffer[file_to_read_from.offset];
-                used_blocks +=1;
-                block = unique_blocks[used_blocks];
-                file_to_read_from.current_block = block as usize;
-                file_to_read_from.offset = 0;
-            }
-            else{
-                buffer[i] = disk_buffer[file_to_read_from.offset];
-                file_to_read_from.offset +=1;
             }
+            return result;
+        }
+        // Like the formatting below, a failure here is reported by the first operation.
+        let _ = result.finish_snapshot();
+        let _ = result.finish_snapshot_delete();
+        let _ = result.finish_rollback();
+        // A corrupted inode bitmap isn't formatted over; the first operation reports it.
+        let _ = result.format_if_needed();
+        result
+    }
+
+    /// Checks the superblock before anything else is read. A disk is either blank or
+    /// formatted with this geometry; one formatted while the transaction is still in
+    /// the journal has a blank superblock until it's replayed.
+    fn check_format(&mut self) -> Option<FileSystemError> {
+        let mut superblock = [0; BLOCK_SIZE];
+        self.disk.read(SUPERBLOCK, &mut superblock);
+        match Geometry::from_superblock(&superblock) {
+            Some(geometry) if geometry == Self::GEOMETRY => None,
+            None if superblock.iter().all(|byte| *byte == 0) => None,
+            _ => Some(FileSystemError::UnsupportedFormat),
+        }
+    }
+
+    /// Gives back the device, e.g. to mount it again, after flushing it.
+    pub fn into_disk(mut self) -> D {
+        self.sync();
+        self.disk
+    }
+
+    pub fn disk(&self) -> &D {
+        &self.disk
+    }
+
+    /// Flushes everything written so far through to the disk. Each operation is
+    /// already crash-safe once it returns; this also writes back whatever a caching
+    /// device is still holding.
+    pub fn sync(&mut self) {
+        // Only an update that has already failed can leave too much staged.
+        let _ = self.commit_transaction();
+        self.disk.flush();
+    }
+
+    /// Flushes the file open as `fd` through to the disk. Metadata is shared between
+    /// files, so this flushes everything `sync` does.
+    pub fn fsync(&mut self, fd: usize) -> FileSystemResult<()> {
+        if self.open.get(fd).copied().flatten().is_none() {
+            return FileSystemResult::Err(FileSystemError::FileNotOpen);
+        }
+        self.sync();
+        FileSystemResult::Ok(())
+    }
+
+    pub fn max_file_size(&self) -> usize {
+        Self::GEOMETRY.max_file_bytes()
+    }
+
+    fn file_capacity(&self) -> usize {
+        Self::GEOMETRY.file_capacity()
+    }
+
+    pub fn num_inode_bytes(&self) -> usize {
+        Inode::<MAX_FILE_BLOCKS>::NUM_BYTES
+    }
+
+    pub fn inodes_per_block(&self) -> usize {
+        Self::GEOMETRY.inodes_per_block()
+    }
+
+    pub fn num_inode_blocks(&self) -> usize {
+        Self::GEOMETRY.inode_blocks()
+    }
+
+    pub fn num_data_blocks(&self) -> usize {
+        Self::GEOMETRY.data_blocks()
+    }
+
+    pub fn num_journal_blocks(&self) -> usize {
+        Self::GEOMETRY.journal_blocks()
+    }
+
+    fn journal_start(&self) -> usize {
+        Self::GEOMETRY.journal_start()
+    }
+
+    pub fn num_inode_entries(&self) -> usize {
+        self.inodes_per_block() * self.num_inode_blocks() * self.num_inode_bytes()
+    }
+
+    pub fn first_data_block(&self) -> usize {
+        Self::GEOMETRY.first_data_block()
+    }
+
+    pub fn num_checksum_blocks(&self) -> usize {
+        Self::GEOMETRY.checksum_blocks()
+    }
+
+    pub fn num_refcount_blocks(&self) -> usize {
+        Self::GEOMETRY.refcount_blocks()
+    }
+
+    fn refcount_start(&self) -> usize {
+        Self::GEOMETRY.refcount_start()
+    }
+
+    fn snapshot_table_block(&self) -> usize {
+        Self::GEOMETRY.snapshot_table_block()
+    }
+
+    pub fn get_directory_buffer(&mut self) -> FileSystemResult<[u8; MAX_FILE_BYTES]> {
+        FileSystemResult::Ok(self.directory.buffer)
+    }
+
+    pub fn open_stuff(&mut self) -> [Option<FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>>; MAX_OPEN] {
+        self.open
+    }
+
+    // Metadata (bitmaps, inode table, directory and attribute blocks) is never written
+    // in place directly. `write_meta` stages blocks for the current transaction and
+    // `read_meta` sees them; `commit_transaction` copies them into the journal, seals
+    // it with a commit record and only then checkpoints them home. File data goes
+    // straight to disk before the commit that makes it reachable.
+    //
+    // Every block outside the journal and the checksum table has a CRC32 in the table,
+    // checked whenever the block is read back from disk. A zero entry means the block
+    // isn't checked: it has never been written, or it's file data being overwritten.
+
+    fn read_meta(&mut self, block: usize, buffer: &mut [u8; BLOCK_SIZE]) -> FileSystemResult<()> {
+        if let Some(error) = self.mount_error {
+            return FileSystemResult::Err(error);
+        }
+        let block = fs_try!(self.view_location(block));
+        match self.staged_slot(block) {
+            Some(slot) => {
+                *buffer = self.journal_blocks[slot];
+                FileSystemResult::Ok(())
+            }
+            None => self.read_data(block, buffer),
+        }
+    }
+
+    /// Stages `buffer` as the new contents of metadata block `block`, with its checksum.
+    fn write_meta(&mut self, block: usize, buffer: &[u8; BLOCK_SIZE]) {
+        self.stage(block, buffer);
+        self.set_checksum(block, block_checksum(buffer));
+    }
+
+    /// Reads a block straight from disk, checking it against its stored checksum.
+    /// Once the transaction has outgrown the journal, what's staged no longer adds up,
+    /// so it's dropped and the operation fails here.
+    fn read_data(&mut self, block: usize, buffer: &mut [u8; BLOCK_SIZE]) -> FileSystemResult<()> {
+        if self.journal_overflowed {
+            self.abort_transaction();
+            return FileSystemResult::Err(FileSystemError::TransactionTooBig);
+        }
+        self.disk.read(block, buffer);
+        let expected = self.stored_checksum(block);
+        if expected != 0 && expected != block_checksum(buffer) {
+            return FileSystemResult::Err(FileSystemError::Corrupted { block });
+        }
+        FileSystemResult::Ok(())
+    }
+
+    /// Writes a file data block straight to disk. Its new checksum is staged, so it
+    /// lands with the commit that makes the block reachable.
+    fn write_data(&mut self, block: usize, buffer: &[u8; BLOCK_SIZE]) {
+        self.disk.write(block, buffer);
+        self.set_checksum(block, block_checksum(buffer));
+    }
+
+    fn stored_checksum(&mut self, block: usize) -> u32 {
+        let (table_block, start) = Self::GEOMETRY.checksum_location(block);
+        let mut buffer = [0; BLOCK_SIZE];
+        self.read_staged(table_block, &mut buffer);
+        u32::from_be_bytes(buffer[start..start + 4].try_into().unwrap())
+    }
+
+    fn set_checksum(&mut self, block: usize, checksum: u32) {
+        let (table_block, start) = Self::GEOMETRY.checksum_location(block);
+        let mut buffer = [0; BLOCK_SIZE];
+        self.read_staged(table_block, &mut buffer);
+        if buffer[start..start + 4] != checksum.to_be_bytes() {
+            buffer[start..start + 4].copy_from_slice(&checksum.to_be_bytes());
+            self.stage(table_block, &buffer);
+        }
+    }
+
+    /// Reads the current contents of `block`, staged or on disk, without checking them.
+    fn read_staged(&mut self, block: usize, buffer: &mut [u8; BLOCK_SIZE]) {
+        match self.staged_slot(block) {
+            Some(slot) => *buffer = self.journal_blocks[slot],
+            None => self.disk.read(block, buffer),
+        }
+    }
+
+    /// Adds `buffer` to the current transaction as the new contents of `block`. Once
+    /// the journal is full, fsck repairs are committed in pieces; any other
+    /// transaction is marked as overflowed and fails when it's committed.
+    fn stage(&mut self, block: usize, buffer: &[u8; BLOCK_SIZE]) {
+        let slot = match self.staged_slot(block) {
+            Some(slot) => slot,
+            None => {
+                if self.journal_len == JOURNAL_CAPACITY {
+                    if !self.split_transactions {
+                        self.journal_overflowed = true;
+                        return;
+                    }
+                    self.write_transaction();
+                }
+                self.journal_len += 1;
+                self.journal_len - 1
+            }
+        };
+        self.journal_targets[slot] = block as u8;
+        self.journal_blocks[slot] = *buffer;
+    }
+
+    fn staged_slot(&self, block: usize) -> Option<usize> {
+        self.journal_targets[..self.journal_len]
+            .iter()
+            .position(|target| *target as usize == block)
+    }
+
+    /// Drops any staged copy of `block`.
+    fn unstage(&mut self, block: usize) {
+        if let Some(slot) = self.staged_slot(block) {
+            self.journal_len -= 1;
+            self.journal_targets[slot] = self.journal_targets[self.journal_len];
+            self.journal_blocks[slot] = self.journal_blocks[self.journal_len];
+        }
+    }
+
+    /// Checksum the commit record carries over the staged block list and contents,
+    /// so a commit torn by a crash is recognised and ignored.
+    fn journal_checksum(&self) -> u32 {
+        journal_checksum(
+            &self.journal_targets[..self.journal_len],
+            &self.journal_blocks[..self.journal_len],
+        )
+    }
+
+    /// Makes everything staged since the last commit durable as one unit. Every public
+    /// operation that changes metadata ends with this; one that fails part way leaves
+    /// its staged blocks for the next commit. A read-only mount keeps the transaction
+    /// it found in the journal staged instead. A transaction that outgrew the journal
+    /// is dropped, failing with `TransactionTooBig`.
+    fn commit_transaction(&mut self) -> FileSystemResult<()> {
+        if self.journal_overflowed {
+            self.abort_transaction();
+            return FileSystemResult::Err(FileSystemError::TransactionTooBig);
+        }
+        self.write_transaction();
+        FileSystemResult::Ok(())
+    }
+
+    /// Commits what's staged if `blocks` more might not fit in the journal, for work
+    /// done in steps that each leave the disk consistent.
+    fn reserve_journal(&mut self, blocks: usize) -> FileSystemResult<()> {
+        if self.journal_len + blocks > JOURNAL_CAPACITY {
+            return self.commit_transaction();
+        }
+        FileSystemResult::Ok(())
+    }
+
+    fn write_transaction(&mut self) {
+        if self.journal_len == 0 || self.mounted_read_only {
+            return;
+        }
+        self.write_journal();
+        self.checkpoint();
+        self.discard_freed();
+    }
+
+    /// Discards the blocks the transaction just committed freed, in runs of adjacent
+    /// blocks. Until the commit, a crash could bring back a file still using them.
+    fn discard_freed(&mut self) {
+        let mut block = 0;
+        while block < NUM_BLOCKS {
+            let count = self.freed[block..]
+                .iter()
+                .take_while(|freed| **freed)
+                .count();
+            if count > 0 {
+                self.disk.discard(block, count);
+            }
+            block += count + 1;
+        }
+        self.freed = [false; NUM_BLOCKS];
+    }
+
+    /// Writes the staged copies to the journal region, then the commit record. The
+    /// device is flushed before the record, so it never lands ahead of the copies or
+    /// the file data the transaction points at, and again after it.
+    fn write_journal(&mut self) {
+        let start = self.journal_start();
+        for slot in 0..self.journal_len {
+            self.disk
+                .write(start + 1 + slot, &self.journal_blocks[slot]);
+        }
+        self.disk.flush();
+        let mut header = [0; BLOCK_SIZE];
+        header[0] = JOURNAL_COMMITTED;
+        header[1] = self.journal_len as u8;
+        header[2..JOURNAL_HEADER_BYTES].copy_from_slice(&self.journal_checksum().to_be_bytes());
+        header[JOURNAL_HEADER_BYTES..JOURNAL_HEADER_BYTES + self.journal_len]
+            .copy_from_slice(&self.journal_targets[..self.journal_len]);
+        self.disk.write(start, &header);
+        self.disk.flush();
+    }
+
+    /// Copies the staged blocks to their home locations and retires the commit record
+    /// once they're durable.
+    fn checkpoint(&mut self) {
+        for slot in 0..self.journal_len {
+            self.disk.write(
+                self.journal_targets[slot] as usize,
+                &self.journal_blocks[slot],
+            );
+        }
+        self.disk.flush();
+        self.disk.write(self.journal_start(), &[0; BLOCK_SIZE]);
+        self.journal_len = 0;
+    }
+
+    /// Drops everything staged since the last commit, for an operation that must not
+    /// leave part of itself behind. The cached directory may hold the dropped changes,
+    /// so it's reloaded on next use, and open descriptors get their inodes back as
+    /// committed.
+    fn abort_transaction(&mut self) {
+        self.journal_len = 0;
+        self.journal_overflowed = false;
+        self.freed = [false; NUM_BLOCKS];
+        self.directory.len = None;
+        for fd in 0..MAX_OPEN {
+            if let Some(mut file) = self.open[fd] {
+                if let FileSystemResult::Ok(inode) = self.read_inode(file.inode_num) {
+                    file.inode = inode;
+                    self.open[fd] = Some(file);
+                }
+            }
+        }
+    }
+
+    /// Finishes a transaction that was committed but not fully checkpointed when the
+    /// disk was last used. A torn commit record is discarded along with its blocks.
+    /// Mounted read-only, the transaction is only staged, so reads see it but the disk
+    /// keeps it for the next writable mount.
+    fn replay_journal(&mut self) {
+        let start = self.journal_start();
+        let mut header = [0; BLOCK_SIZE];
+        self.disk.read(start, &mut header);
+        if header[0] != JOURNAL_COMMITTED {
+            return;
+        }
+        let len = (header[1] as usize).min(JOURNAL_CAPACITY);
+        self.journal_len = len;
+        self.journal_targets[..len]
+            .copy_from_slice(&header[JOURNAL_HEADER_BYTES..JOURNAL_HEADER_BYTES + len]);
+        for slot in 0..len {
+            self.disk
+                .read(start + 1 + slot, &mut self.journal_blocks[slot]);
+        }
+        if !journal_intact(Self::GEOMETRY, &header, &self.journal_blocks[..len]) {
+            self.discard_journal();
+        } else if !self.mounted_read_only {
+            self.checkpoint();
+        }
+    }
+
+    /// Forgets a torn transaction, clearing its header unless mounted read-only.
+    fn discard_journal(&mut self) {
+        self.journal_len = 0;
+        if !self.mounted_read_only {
+            self.disk.write(self.journal_start(), &[0; BLOCK_SIZE]);
+        }
+    }
+
+    fn format_if_needed(&mut self) -> FileSystemResult<()> {
+        if fs_try!(self.read_meta_with(INODE_FULL_BLOCK, |bitmap| bitmap[0] & 1 != 0)) {
+            return FileSystemResult::Ok(());
+        }
+        fs_try!(self.write_empty_layout());
+        self.commit_transaction()
+    }
+
+    pub fn get_directory(&mut self) -> FileSystemResult<()> {
+        fs_try!(self.load_directory());
+        FileSystemResult::Ok(())
+    }
+
+    fn check_access(
+        &mut self,
+        inode_num: usize,
+        caller: Credentials,
+        access: u16,
+    ) -> FileSystemResult<()> {
+        if fs_try!(self.read_inode(inode_num)).permits(caller, access) {
+            FileSystemResult::Ok(())
+        } else {
+            FileSystemResult::Err(FileSystemError::PermissionDenied)
+        }
+    }
+
+    fn open_inode(
+        &mut self,
+        inode_num: usize,
+        caller: Credentials,
+        reading: bool,
+        writing: bool,
+        append: bool,
+    ) -> FileSystemResult<usize> {
+        let access = if reading { MODE_READ } else { MODE_WRITE };
+        fs_try!(self.check_access(inode_num, caller, access));
+        if self.open_inodes[inode_num] {
+            return FileSystemResult::Err(FileSystemError::AlreadyOpen);
+        }
+        let fd = fs_try!(self.free_descriptor());
+        let inode = fs_try!(self.read_inode(inode_num));
+        let offset = if append {
+            inode.bytes_stored as usize
+        } else {
+            0
+        };
+        let mut file = FileInfo {
+            inode,
+            inode_num,
+            current_block: 0,
+            offset,
+            writing,
+            reading,
+            block_buffer: [0; BLOCK_SIZE],
+        };
+        fs_try!(self.load_file_block(&mut file, offset / BLOCK_SIZE));
+        self.open[fd] = Some(file);
+        self.open_inodes[inode_num] = true;
+        FileSystemResult::Ok(fd)
+    }
+
+    /// Finds an unused slot in the open file table.
+    fn free_descriptor(&self) -> FileSystemResult<usize> {
+        match self.open.iter().position(|file| file.is_none()) {
+            Some(fd) => FileSystemResult::Ok(fd),
+            None => FileSystemResult::Err(FileSystemError::TooManyOpen),
+        }
+    }
+
+    /// Points `file` at its `index`th block, reading it into the descriptor's buffer.
+    fn load_file_block(
+        &mut self,
+        file: &mut FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>,
+        index: usize,
+    ) -> FileSystemResult<()> {
+        file.current_block = index;
+        file.block_buffer = [0; BLOCK_SIZE];
+        if file.inode.inline {
+            if index == 0 {
+                let len = Self::GEOMETRY.inline_bytes();
+                file.block_buffer[..len].copy_from_slice(&file.inode.blocks[..len]);
+            }
+        } else if index < MAX_FILE_BLOCKS && file.inode.blocks[index] != 0 {
+            fs_try!(self.read_data(file.inode.blocks[index] as usize, &mut file.block_buffer));
+        }
+        FileSystemResult::Ok(())
+    }
+
+    /// Checks that `filename` can name a file: non-empty, short enough, and free of
+    /// `/` and NUL. Any other UTF-8 is fine.
+    fn check_filename(&self, filename: &str) -> FileSystemResult<()> {
+        check_filename(filename, MAX_FILENAME_BYTES)
+    }
+
+    pub fn open_read(&mut self, filename: &str) -> FileSystemResult<usize> {
+        self.open_read_as(filename, Credentials::ROOT)
+    }
+
+    pub fn open_read_as(&mut self, filename: &str, caller: Credentials) -> FileSystemResult<usize> {
+        let inode_num = fs_try!(self.lookup(filename));
+        self.open_inode(inode_num, caller, true, false, false)
+    }
+
+    pub fn open_create(&mut self, filename: &str) -> FileSystemResult<usize> {
+        self.open_create_as(filename, Credentials::ROOT)
+    }
+
+    /// Creates or truncates `filename` for writing. Truncating needs write access to the
+    /// file; creating needs write access to the directory, and `caller` owns the new file.
+    /// Without a free descriptor the disk is left as it was. Truncating a file whose
+    /// blocks are spread over more bitmap and checksum blocks than `JOURNAL_CAPACITY`
+    /// covers fails with `TransactionTooBig`.
+    pub fn open_create_as(
+        &mut self,
+        filename: &str,
+        caller: Credentials,
+    ) -> FileSystemResult<usize> {
+        fs_try!(self.check_writable());
+        fs_try!(self.free_descriptor());
+        let mut name = [0; MAX_FILENAME_BYTES];
+        let (len, found) = fs_try!(self.resolve(filename, &mut name));
+        if let Some(inode_num) = found {
+            fs_try!(self.check_access(inode_num, caller, MODE_WRITE));
+            if self.open_inodes[inode_num] {
+                return FileSystemResult::Err(FileSystemError::AlreadyOpen);
+            }
+            fs_try!(self.truncate(inode_num));
+            fs_try!(self.commit_transaction());
+            return self.open_inode(inode_num, caller, false, true, false);
+        }
+
+        fs_try!(self.check_access(DIRECTORY_INODE, caller, MODE_WRITE));
+        let inode_num = fs_try!(self.create_inode(&name[..len], FileType::File, caller, 0));
+        fs_try!(self.commit_transaction());
+        self.open_inode(inode_num, caller, false, true, false)
+    }
+
+    /// Empties `inode_num`, releasing its data blocks; it's stored inline again until it
+    /// next outgrows the inode.
+    fn truncate(&mut self, inode_num: usize) -> FileSystemResult<()> {
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        for block in inode.data_blocks() {
+            if *block != 0 {
+                fs_try!(self.release_block(*block));
+            }
+        }
+        inode.blocks = [0; MAX_FILE_BLOCKS];
+        inode.inline = true;
+        inode.bytes_stored = 0;
+        inode.mtime = self.clock.now();
+        self.write_inode(inode_num, &inode)
+    }
+
+    pub fn open_append(&mut self, filename: &str) -> FileSystemResult<usize> {
+        self.open_append_as(filename, Credentials::ROOT)
+    }
+
+    pub fn open_append_as(
+        &mut self,
+        filename: &str,
+        caller: Credentials,
+    ) -> FileSystemResult<usize> {
+        fs_try!(self.check_writable());
+        let inode_num = fs_try!(self.lookup(filename));
+        self.open_inode(inode_num, caller, false, true, true)
+    }
+
+    /// Sets the permission bits of `filename`. Only its owner or the superuser may.
+    pub fn chmod(
+        &mut self,
+        filename: &str,
+        mode: u16,
+        caller: Credentials,
+    ) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        let inode_num = fs_try!(self.lookup(filename));
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        if caller.uid != Credentials::ROOT.uid && caller.uid != inode.uid {
+            return FileSystemResult::Err(FileSystemError::PermissionDenied);
+        }
+        inode.mode = mode & 0o777;
+        fs_try!(self.write_inode(inode_num, &inode));
+        self.commit_transaction()
+    }
+
+    /// Gives `filename` a new owner and group. Only the superuser may.
+    pub fn chown(
+        &mut self,
+        filename: &str,
+        uid: u16,
+        gid: u16,
+        caller: Credentials,
+    ) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        if caller.uid != Credentials::ROOT.uid {
+            return FileSystemResult::Err(FileSystemError::PermissionDenied);
+        }
+        let inode_num = fs_try!(self.lookup(filename));
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        inode.uid = uid;
+        inode.gid = gid;
+        fs_try!(self.write_inode(inode_num, &inode));
+        self.commit_transaction()
+    }
+
+    pub fn link(&mut self, existing: &str, new: &str) -> FileSystemResult<()> {
+        self.link_as(existing, new, Credentials::ROOT)
+    }
+
+    /// Gives the file `existing` the additional name `new`; both names share one inode.
+    /// Needs write access to the directory.
+    pub fn link_as(
+        &mut self,
+        existing: &str,
+        new: &str,
+        caller: Credentials,
+    ) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        fs_try!(self.check_filename(existing));
+        fs_try!(self.check_filename(new));
+        fs_try!(self.check_access(DIRECTORY_INODE, caller, MODE_WRITE));
+        fs_try!(self.load_directory());
+        let inode_num = match self.directory.find_entry(existing.as_bytes()) {
+            Some(offset) => self.directory.entry_inode(offset),
+            None => return FileSystemResult::Err(FileSystemError::FileNotFound),
+        };
+        if self.directory.find_entry(new.as_bytes()).is_some() {
+            return FileSystemResult::Err(FileSystemError::FileExists);
+        }
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        if inode.links == u8::MAX {
+            return FileSystemResult::Err(FileSystemError::TooManyLinks);
+        }
+        fs_try!(self.add_entry(new.as_bytes(), inode_num));
+        inode.links += 1;
+        fs_try!(self.write_inode(inode_num, &inode));
+        self.commit_transaction()
+    }
+
+    pub fn symlink(&mut self, target: &str, linkpath: &str) -> FileSystemResult<()> {
+        self.symlink_as(target, linkpath, Credentials::ROOT)
+    }
+
+    /// Creates `linkpath` as a symbolic link whose contents are the name `target`.
+    /// The target doesn't need to exist yet. Needs write access to the directory, and
+    /// `caller` owns the link.
+    pub fn symlink_as(
+        &mut self,
+        target: &str,
+        linkpath: &str,
+        caller: Credentials,
+    ) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        fs_try!(self.check_filename(target));
+        fs_try!(self.check_filename(linkpath));
+        fs_try!(self.check_access(DIRECTORY_INODE, caller, MODE_WRITE));
+        fs_try!(self.load_directory());
+        if self.directory.find_entry(linkpath.as_bytes()).is_some() {
+            return FileSystemResult::Err(FileSystemError::FileExists);
+        }
+        let num_blocks = if target.len() <= Self::GEOMETRY.inline_bytes() {
+            0
+        } else {
+            target.len().div_ceil(BLOCK_SIZE)
+        };
+        let inode_num =
+            fs_try!(self.create_inode(linkpath.as_bytes(), FileType::Symlink, caller, num_blocks));
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        if inode.inline {
+            inode.blocks[..target.len()].copy_from_slice(target.as_bytes());
+        } else {
+            for (i, chunk) in target.as_bytes().chunks(BLOCK_SIZE).enumerate() {
+                let mut buffer = [0; BLOCK_SIZE];
+                buffer[..chunk.len()].copy_from_slice(chunk);
+                self.write_data(inode.blocks[i] as usize, &buffer);
+            }
+        }
+        inode.bytes_stored = target.len() as u16;
+        fs_try!(self.write_inode(inode_num, &inode));
+        self.commit_transaction()
+    }
+
+    /// Copies the target of the symbolic link `path` into `buffer`, returning its length.
+    pub fn readlink(&mut self, path: &str, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        fs_try!(self.check_filename(path));
+        fs_try!(self.load_directory());
+        let inode_num = match self.directory.find_entry(path.as_bytes()) {
+            Some(offset) => self.directory.entry_inode(offset),
+            None => return FileSystemResult::Err(FileSystemError::FileNotFound),
+        };
+        let inode = fs_try!(self.read_inode(inode_num));
+        if inode.kind != FileType::Symlink {
+            return FileSystemResult::Err(FileSystemError::NotASymlink);
+        }
+        self.read_inode_data(&inode, buffer)
+    }
+
+    /// Describes the file `filename` names, following symbolic links.
+    pub fn stat(&mut self, filename: &str) -> FileSystemResult<Stat> {
+        self.stat_file(filename)
+    }
+
+    /// Loads the attribute block of `inode`, or an empty one if it has none.
+    fn read_xattrs(
+        &mut self,
+        inode: &Inode<MAX_FILE_BLOCKS>,
+    ) -> FileSystemResult<[u8; BLOCK_SIZE]> {
+        let mut buffer = [0; BLOCK_SIZE];
+        if inode.xattr_block != 0 {
+            fs_try!(self.read_meta(inode.xattr_block as usize, &mut buffer));
+        }
+        FileSystemResult::Ok(buffer)
+    }
+
+    /// Stores `buffer` as the attribute block of `inode_num`, allocating the block on
+    /// first use or while a snapshot shares it, and releasing it once the last
+    /// attribute is gone.
+    fn write_xattrs(
+        &mut self,
+        inode_num: usize,
+        buffer: &[u8; BLOCK_SIZE],
+    ) -> FileSystemResult<()> {
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        if xattr_end(buffer) == 0 {
+            if inode.xattr_block != 0 {
+                fs_try!(self.release_block(inode.xattr_block));
+                inode.xattr_block = 0;
+                fs_try!(self.write_inode(inode_num, &inode));
+            }
+            return FileSystemResult::Ok(());
+        }
+        if inode.xattr_block == 0 || fs_try!(self.block_refs(inode.xattr_block)) > 1 {
+            let block = fs_try!(self.alloc_block());
+            if inode.xattr_block != 0 {
+                fs_try!(self.release_block(inode.xattr_block));
+            }
+            inode.xattr_block = block;
+            fs_try!(self.write_inode(inode_num, &inode));
+        }
+        self.write_meta(inode.xattr_block as usize, buffer);
+        FileSystemResult::Ok(())
+    }
+
+    pub fn set_xattr(&mut self, filename: &str, name: &str, value: &[u8]) -> FileSystemResult<()> {
+        self.set_xattr_as(filename, name, value, Credentials::ROOT)
+    }
+
+    /// Attaches the attribute `name` with contents `value` to `filename`, replacing
+    /// any previous value. Needs write access to the file.
+    pub fn set_xattr_as(
+        &mut self,
+        filename: &str,
+        name: &str,
+        value: &[u8],
+        caller: Credentials,
+    ) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        if name.is_empty() {
+            return FileSystemResult::Err(FileSystemError::InvalidXattrName);
+        }
+        if name.len() > MAX_XATTR_NAME_BYTES {
+            return FileSystemResult::Err(FileSystemError::XattrNameTooLong);
+        }
+        if value.len() > MAX_XATTR_VALUE_BYTES {
+            return FileSystemResult::Err(FileSystemError::XattrValueTooLong);
+        }
+        let inode_num = fs_try!(self.lookup(filename));
+        fs_try!(self.check_access(inode_num, caller, MODE_WRITE));
+        let inode = fs_try!(self.read_inode(inode_num));
+        let mut buffer = fs_try!(self.read_xattrs(&inode));
+        if let Some(start) = xattr_find(&buffer, name.as_bytes()) {
+            xattr_remove(&mut buffer, start);
+        }
+        let end = xattr_end(&buffer);
+        let record_len = 2 + name.len() + value.len();
+        if end + record_len > BLOCK_SIZE {
+            return FileSystemResult::Err(FileSystemError::XattrSpaceFull);
+        }
+        buffer[end] = name.len() as u8;
+        buffer[end + 1] = value.len() as u8;
+        buffer[end + 2..end + 2 + name.len()].copy_from_slice(name.as_bytes());
+        buffer[end + 2 + name.len()..end + record_len].copy_from_slice(value);
+        let result = self.write_xattrs(inode_num, &buffer);
+        self.commit_or_abort(result)
+    }
+
+    pub fn get_xattr(
+        &mut self,
+        filename: &str,
+        name: &str,
+        buffer: &mut [u8],
+    ) -> FileSystemResult<usize> {
+        self.get_xattr_as(filename, name, buffer, Credentials::ROOT)
+    }
+
+    /// Copies the value of attribute `name` into `buffer`, returning the value's length.
+    /// Needs read access to the file.
+    pub fn get_xattr_as(
+        &mut self,
+        filename: &str,
+        name: &str,
+        buffer: &mut [u8],
+        caller: Credentials,
+    ) -> FileSystemResult<usize> {
+        let inode_num = fs_try!(self.lookup(filename));
+        fs_try!(self.check_access(inode_num, caller, MODE_READ));
+        let inode = fs_try!(self.read_inode(inode_num));
+        let attrs = fs_try!(self.read_xattrs(&inode));
+        match xattr_find(&attrs, name.as_bytes()) {
+            Some(start) => {
+                let name_len = attrs[start] as usize;
+                let value_len = attrs[start + 1] as usize;
+                let value = &attrs[start + 2 + name_len..start + 2 + name_len + value_len];
+                let len = value_len.min(buffer.len());
+                buffer[..len].copy_from_slice(&value[..len]);
+                FileSystemResult::Ok(value_len)
+            }
+            None => FileSystemResult::Err(FileSystemError::XattrNotFound),
+        }
+    }
+
+    pub fn list_xattrs(&mut self, filename: &str, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        self.list_xattrs_as(filename, buffer, Credentials::ROOT)
+    }
+
+    /// Writes the attribute names of `filename` into `buffer`, each followed by a NUL.
+    /// Returns the length of the full list, which may exceed what fit in `buffer`.
+    /// Needs read access to the file.
+    pub fn list_xattrs_as(
+        &mut self,
+        filename: &str,
+        buffer: &mut [u8],
+        caller: Credentials,
+    ) -> FileSystemResult<usize> {
+        let inode_num = fs_try!(self.lookup(filename));
+        fs_try!(self.check_access(inode_num, caller, MODE_READ));
+        let inode = fs_try!(self.read_inode(inode_num));
+        let attrs = fs_try!(self.read_xattrs(&inode));
+        let mut total = 0;
+        let mut start = 0;
+        while start < xattr_end(&attrs) {
+            let name_len = attrs[start] as usize;
+            for byte in attrs[start + 2..start + 2 + name_len]
+                .iter()
+                .chain([0].iter())
+            {
+                if total < buffer.len() {
+                    buffer[total] = *byte;
+                }
+                total += 1;
+            }
+            start += 2 + name_len + attrs[start + 1] as usize;
+        }
+        FileSystemResult::Ok(total)
+    }
+
+    pub fn remove_xattr(&mut self, filename: &str, name: &str) -> FileSystemResult<()> {
+        self.remove_xattr_as(filename, name, Credentials::ROOT)
+    }
+
+    /// Removes attribute `name` from `filename`. Needs write access to the file.
+    pub fn remove_xattr_as(
+        &mut self,
+        filename: &str,
+        name: &str,
+        caller: Credentials,
+    ) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        let inode_num = fs_try!(self.lookup(filename));
+        fs_try!(self.check_access(inode_num, caller, MODE_WRITE));
+        let inode = fs_try!(self.read_inode(inode_num));
+        let mut buffer = fs_try!(self.read_xattrs(&inode));
+        match xattr_find(&buffer, name.as_bytes()) {
+            Some(start) => {
+                xattr_remove(&mut buffer, start);
+                let result = self.write_xattrs(inode_num, &buffer);
+                self.commit_or_abort(result)
+            }
+            None => FileSystemResult::Err(FileSystemError::XattrNotFound),
+        }
+    }
+
+    pub fn copy(&mut self, src: &str, dst: &str) -> FileSystemResult<()> {
+        self.copy_as(src, dst, Credentials::ROOT)
+    }
+
+    /// Creates `dst` as a new file holding the contents and permission bits of `src`,
+    /// copying block by block without going through a descriptor. Needs read access to
+    /// `src` and write access to the directory; `caller` owns the copy. If anything
+    /// fails, `dst` isn't created at all; the whole copy is one transaction, so a
+    /// file whose checksums span more than `JOURNAL_CAPACITY` blocks can't be copied.
+    pub fn copy_as(&mut self, src: &str, dst: &str, caller: Credentials) -> FileSystemResult<()> {
+        let source = fs_try!(self.clone_source(src, dst, caller));
+        let num_blocks = source
+            .data_blocks()
+            .iter()
+            .filter(|block| **block != 0)
+            .count();
+        let result = match self.create_inode(dst.as_bytes(), FileType::File, caller, num_blocks) {
+            FileSystemResult::Ok(dst_num) => self.copy_data(&source, dst_num),
+            FileSystemResult::Err(e) => FileSystemResult::Err(e),
+        };
+        self.commit_or_abort(result)
+    }
+
+    pub fn reflink(&mut self, src: &str, dst: &str) -> FileSystemResult<()> {
+        self.reflink_as(src, dst, Credentials::ROOT)
+    }
+
+    /// Creates `dst` as a clone of `src` that shares its data blocks instead of copying
+    /// them. Each file gets a private copy of a shared block the first time it writes
+    /// to it. Access rules and failure behaviour are those of `copy_as`, except that
+    /// the journal limit applies to the reference-count blocks rather than checksums.
+    pub fn reflink_as(
+        &mut self,
+        src: &str,
+        dst: &str,
+        caller: Credentials,
+    ) -> FileSystemResult<()> {
+        let source = fs_try!(self.clone_source(src, dst, caller));
+        for block in source.data_blocks().iter().filter(|block| **block != 0) {
+            if fs_try!(self.block_refs(*block)) == u8::MAX {
+                return FileSystemResult::Err(FileSystemError::TooManyLinks);
+            }
+        }
+        let result = match self.create_inode(dst.as_bytes(), FileType::File, caller, 0) {
+            FileSystemResult::Ok(dst_num) => self.share_data(&source, dst_num),
+            FileSystemResult::Err(e) => FileSystemResult::Err(e),
+        };
+        self.commit_or_abort(result)
+    }
+
+    /// Checks that `caller` may clone `src` as the new name `dst`, and returns the
+    /// inode to clone. Anything still staged is committed first, so a failed clone
+    /// only drops its own changes.
+    fn clone_source(
+        &mut self,
+        src: &str,
+        dst: &str,
+        caller: Credentials,
+    ) -> FileSystemResult<Inode<MAX_FILE_BLOCKS>> {
+        fs_try!(self.check_writable());
+        let src_num = fs_try!(self.lookup(src));
+        fs_try!(self.check_access(src_num, caller, MODE_READ));
+        fs_try!(self.check_filename(dst));
+        fs_try!(self.load_directory());
+        if self.directory.find_entry(dst.as_bytes()).is_some() {
+            return FileSystemResult::Err(FileSystemError::FileExists);
+        }
+        fs_try!(self.check_access(DIRECTORY_INODE, caller, MODE_WRITE));
+        let source = fs_try!(self.current_inode(src_num));
+        fs_try!(self.commit_transaction());
+        FileSystemResult::Ok(source)
+    }
+
+    /// Commits the current transaction if `result` is a success and drops it otherwise.
+    fn commit_or_abort(&mut self, result: FileSystemResult<()>) -> FileSystemResult<()> {
+        match result {
+            FileSystemResult::Ok(()) => self.commit_transaction(),
+            FileSystemResult::Err(_) => {
+                self.abort_transaction();
+                result
+            }
+        }
+    }
+
+    /// Fills the freshly created inode `inode_num` with a copy of `source`, whose
+    /// allocated blocks it already has as many of as it needs.
+    fn copy_data(
+        &mut self,
+        source: &Inode<MAX_FILE_BLOCKS>,
+        inode_num: usize,
+    ) -> FileSystemResult<()> {
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        if source.inline {
+            inode.blocks = source.blocks;
+        } else {
+            let mut fresh = inode.blocks.into_iter();
+            let mut buffer = [0; BLOCK_SIZE];
+            for (block, src_block) in inode.blocks.iter_mut().zip(source.blocks) {
+                *block = 0;
+                if src_block != 0 {
+                    *block = fresh.next().unwrap_or(0);
+                    fs_try!(self.read_data(src_block as usize, &mut buffer));
+                    self.write_data(*block as usize, &buffer);
+                }
+            }
+        }
+        inode.inline = source.inline;
+        inode.bytes_stored = source.bytes_stored;
+        inode.mode = source.mode;
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// Points the freshly created, empty inode `inode_num` at `source`'s data blocks.
+    fn share_data(
+        &mut self,
+        source: &Inode<MAX_FILE_BLOCKS>,
+        inode_num: usize,
+    ) -> FileSystemResult<()> {
+        let mut inode = fs_try!(self.read_inode(inode_num));
+        for block in source.data_blocks().iter().filter(|block| **block != 0) {
+            let refs = fs_try!(self.block_refs(*block));
+            fs_try!(self.set_block_refs(*block, refs + 1));
+        }
+        inode.blocks = source.blocks;
+        inode.inline = source.inline;
+        inode.bytes_stored = source.bytes_stored;
+        inode.mode = source.mode;
+        self.write_inode(inode_num, &inode)
+    }
+
+    pub fn delete(&mut self, filename: &str) -> FileSystemResult<()> {
+        self.delete_as(filename, Credentials::ROOT)
+    }
+
+    /// Removes the name `filename`. The file's blocks are only freed once its last
+    /// name is gone and no descriptor still has it open. Needs write access to the
+    /// directory, and unless `caller` is root, to own the file. Freeing a file spread over more bitmap and checksum blocks than
+    /// `JOURNAL_CAPACITY` covers fails with `TransactionTooBig`, as does rewriting a
+    /// directory larger than the journal.
+    pub fn delete_as(&mut self, filename: &str, caller: Credentials) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        fs_try!(self.check_filename(filename));
+        fs_try!(self.check_access(DIRECTORY_INODE, caller, MODE_WRITE));
+        fs_try!(self.load_directory());
+        let offset = match self.directory.find_entry(filename.as_bytes()) {
+            Some(offset) => offset,
+            None => return FileSystemResult::Err(FileSystemError::FileNotFound),
+        };
+        let dir_inode = fs_try!(self.read_inode(DIRECTORY_INODE));
+        let inode = fs_try!(self.read_inode(self.directory().entry_inode(offset)));
+        if dir_inode.mode & MODE_STICKY != 0
+            && caller.uid != Credentials::ROOT.uid
+            && caller.uid != inode.uid
+            && caller.uid != dir_inode.uid
+        {
+            return FileSystemResult::Err(FileSystemError::PermissionDenied);
+        }
+        fs_try!(self.remove_name(offset));
+        self.commit_transaction()
+    }
+
+    pub fn read(&mut self, fd: usize, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        let mut file = match self.open.get(fd).copied().flatten() {
+            Some(file) => file,
+            None => return FileSystemResult::Err(FileSystemError::FileNotOpen),
+        };
+        if file.writing {
+            return FileSystemResult::Err(FileSystemError::NotOpenForRead);
+        }
+
+        let size = file.inode.bytes_stored as usize;
+        let mut bytes_read = 0;
+        while bytes_read < buffer.len() && file.offset < size {
+            let index = file.offset / BLOCK_SIZE;
+            if index != file.current_block {
+                fs_try!(self.load_file_block(&mut file, index));
+            }
+            buffer[bytes_read] = file.block_buffer[file.offset % BLOCK_SIZE];
+            file.offset += 1;
+            bytes_read += 1;
+        }
+        // Persisted by `close`, so reads don't each rewrite the inode table.
+        file.inode.atime = self.clock.now();
+        self.open[fd] = Some(file);
+        FileSystemResult::Ok(bytes_read)
+    }
+
+    /// Writes `buffer` at the position of `fd`, copying any block it shares with a
+    /// reflink or snapshot first. A write over more scattered blocks than the journal
+    /// has room for is committed a block at a time, so a crash can leave part of it.
+    pub fn write(&mut self, fd: usize, buffer: &[u8]) -> FileSystemResult<()> {
+        let mut file = match self.open.get(fd).copied().flatten() {
+            Some(file) => file,
+            None => return FileSystemResult::Err(FileSystemError::FileNotOpen),
+        };
+        if file.reading {
+            return FileSystemResult::Err(FileSystemError::NotOpenForWrite);
+        }
+        if buffer.is_empty() {
+            return FileSystemResult::Ok(());
+        }
+
+        let end = file.offset + buffer.len();
+        if end > self.file_capacity() {
+            return FileSystemResult::Err(FileSystemError::FileTooBig);
+        }
+        if file.inode.inline && end <= Self::GEOMETRY.inline_bytes() {
+            return self.write_inline(fd, file, buffer);
+        }
+        let mut blocks_needed = 0;
+        // Inline contents moving out need a block unless the write lands in it anyway.
+        if file.inode.inline && file.inode.bytes_stored > 0 && file.offset >= BLOCK_SIZE {
+            blocks_needed += 1;
+        }
+        for index in file.offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+            let block = file.inode.data_blocks().get(index).copied().unwrap_or(0);
+            if block == 0 || fs_try!(self.block_refs(block)) > 1 {
+                blocks_needed += 1;
+            }
+        }
+        if blocks_needed > fs_try!(self.num_free_blocks()) {
+            return FileSystemResult::Err(FileSystemError::DiskFull);
+        }
+        let prepared = match self.spill_inline(&mut file) {
+            FileSystemResult::Ok(()) => self.unshare_blocks(&mut file, end),
+            FileSystemResult::Err(e) => FileSystemResult::Err(e),
+        };
+        if let FileSystemResult::Err(e) = prepared {
+            self.abort_transaction();
+            return FileSystemResult::Err(e);
+        }
+        // Blocks about to be overwritten in place stop being checked until the new
+        // checksums commit, so a crash part way through can't leave a false mismatch.
+        for index in file.offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+            if file.inode.blocks[index] != 0 {
+                fs_try!(self.reserve_journal(1));
+                self.set_checksum(file.inode.blocks[index] as usize, 0);
+            }
+        }
+        fs_try!(self.commit_transaction());
+
+        let mut dirty = false;
+        for byte in buffer {
+            let index = file.offset / BLOCK_SIZE;
+            if index != file.current_block {
+                if dirty {
+                    let block = file.inode.blocks[file.current_block] as usize;
+                    self.write_data(block, &file.block_buffer);
+                    if self.journal_len + JOURNAL_STEP_BLOCKS > JOURNAL_CAPACITY {
+                        fs_try!(self.commit_write(&mut file));
+                    }
+                }
+                fs_try!(self.load_file_block(&mut file, index));
+            }
+            if file.inode.blocks[index] == 0 {
+                file.inode.blocks[index] = fs_try!(self.alloc_block());
+            }
+            file.block_buffer[file.offset % BLOCK_SIZE] = *byte;
+            file.offset += 1;
+            dirty = true;
+        }
+        let block = file.inode.blocks[file.current_block] as usize;
+        self.write_data(block, &file.block_buffer);
+        fs_try!(self.commit_write(&mut file));
+        self.open[fd] = Some(file);
+        FileSystemResult::Ok(())
+    }
+
+    /// Records in the inode how far `file` has been written, and commits it.
+    fn commit_write(
+        &mut self,
+        file: &mut FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>,
+    ) -> FileSystemResult<()> {
+        if file.offset > file.inode.bytes_stored as usize {
+            file.inode.bytes_stored = file.offset as u16;
+        }
+        file.inode.mtime = self.clock.now();
+        fs_try!(self.write_inode(file.inode_num, &file.inode));
+        self.commit_transaction()
+    }
+
+    /// Writes `buffer` into the inline contents of `file`, which it fits in.
+    fn write_inline(
+        &mut self,
+        fd: usize,
+        mut file: FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>,
+        buffer: &[u8],
+    ) -> FileSystemResult<()> {
+        let end = file.offset + buffer.len();
+        file.inode.blocks[file.offset..end].copy_from_slice(buffer);
+        file.offset = end;
+        if end > file.inode.bytes_stored as usize {
+            file.inode.bytes_stored = end as u16;
+        }
+        file.inode.mtime = self.clock.now();
+        fs_try!(self.load_file_block(&mut file, end / BLOCK_SIZE));
+        fs_try!(self.write_inode(file.inode_num, &file.inode));
+        fs_try!(self.commit_transaction());
+        self.open[fd] = Some(file);
+        FileSystemResult::Ok(())
+    }
+
+    /// Moves the inline contents of `file` out to a data block of their own, ahead of a
+    /// write the inode has no room for.
+    fn spill_inline(
+        &mut self,
+        file: &mut FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>,
+    ) -> FileSystemResult<()> {
+        if !file.inode.inline {
+            return FileSystemResult::Ok(());
+        }
+        let len = file.inode.bytes_stored as usize;
+        let mut buffer = [0; BLOCK_SIZE];
+        buffer[..len].copy_from_slice(&file.inode.blocks[..len]);
+        file.inode.blocks = [0; MAX_FILE_BLOCKS];
+        file.inode.inline = false;
+        if len > 0 {
+            file.inode.blocks[0] = fs_try!(self.alloc_block());
+            self.write_data(file.inode.blocks[0] as usize, &buffer);
+        }
+        self.write_inode(file.inode_num, &file.inode)
+    }
+
+    /// Gives `file` a private copy of each shared block it's about to write, from its
+    /// offset up to `end`, leaving the other owners the original. Each copy is a step
+    /// of its own, committed as the journal fills.
+    fn unshare_blocks(
+        &mut self,
+        file: &mut FileInfo<MAX_FILE_BLOCKS, BLOCK_SIZE>,
+        end: usize,
+    ) -> FileSystemResult<()> {
+        let mut buffer = [0; BLOCK_SIZE];
+        for index in file.offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+            let block = file.inode.blocks[index];
+            if block == 0 || fs_try!(self.block_refs(block)) == 1 {
+                continue;
+            }
+            fs_try!(self.reserve_journal(JOURNAL_STEP_BLOCKS));
+            let copy = fs_try!(self.alloc_block());
+            fs_try!(self.read_data(block as usize, &mut buffer));
+            self.write_data(copy as usize, &buffer);
+            fs_try!(self.release_block(block));
+            file.inode.blocks[index] = copy;
+            fs_try!(self.write_inode(file.inode_num, &file.inode));
+        }
+        FileSystemResult::Ok(())
+    }
+
+    /// Moves the position of `fd` to `offset` bytes from the start of the file. It may
+    /// go past the end: a write there leaves a hole that reads back as zeros and takes
+    /// no blocks until written.
+    pub fn seek(&mut self, fd: usize, offset: usize) -> FileSystemResult<()> {
+        let mut file = match self.open.get(fd).copied().flatten() {
+            Some(file) => file,
+            None => return FileSystemResult::Err(FileSystemError::FileNotOpen),
+        };
+        if offset > self.file_capacity() {
+            return FileSystemResult::Err(FileSystemError::FileTooBig);
+        }
+        file.offset = offset;
+        if offset / BLOCK_SIZE != file.current_block {
+            fs_try!(self.load_file_block(&mut file, offset / BLOCK_SIZE));
+        }
+        self.open[fd] = Some(file);
+        FileSystemResult::Ok(())
+    }
+
+    pub fn close(&mut self, fd: usize) -> FileSystemResult<()> {
+        let file = match self.open.get(fd).copied().flatten() {
+            Some(file) => file,
+            None => return FileSystemResult::Err(FileSystemError::FileNotFound),
+        };
+        self.open[fd] = None;
+        self.open_inodes[file.inode_num] = false;
+        if self.read_only() {
+            return FileSystemResult::Ok(());
+        }
+        let mut inode = fs_try!(self.read_inode(file.inode_num));
+        if inode.links == 0 {
+            fs_try!(self.release_inode(file.inode_num));
+        } else if inode.atime != file.inode.atime {
+            inode.atime = file.inode.atime;
+            fs_try!(self.write_inode(file.inode_num, &inode));
+        }
+        self.commit_transaction()
+    }
+
+    /// Lists the directory in the order names were added. Entries a damaged directory
+    /// can't describe are skipped; `fsck` reports and removes them.
+    pub fn read_dir(&mut self) -> FileSystemResult<ReadDir<'_>> {
+        let mut listed = core::mem::replace(&mut self.listed_inodes, [None; MAX_FILES_STORED]);
+        let result = self.list_inodes(&mut listed);
+        self.listed_inodes = listed;
+        fs_try!(result);
+        FileSystemResult::Ok(ReadDir {
+            entries: self.directory.entries(),
+            inodes: &self.listed_inodes,
+        })
+    }
+
+    /// Lists the files whose names match the glob `pattern`; see `glob_match`.
+    pub fn find<'p>(&mut self, pattern: &'p str) -> FileSystemResult<Find<'_, 'p>> {
+        FileSystemResult::Ok(Find {
+            entries: fs_try!(self.read_dir()),
+            pattern,
+        })
+    }
+
+    /// Copies the names from `read_dir` into fixed-size, zero-padded buffers.
+    pub fn list_directory(
+        &mut self,
+    ) -> FileSystemResult<(usize, [[u8; MAX_FILENAME_BYTES]; MAX_FILES_STORED])> {
+        let mut count = 0;
+        let mut files = [[0; MAX_FILENAME_BYTES]; MAX_FILES_STORED];
+        for entry in fs_try!(self.read_dir()).take(MAX_FILES_STORED) {
+            files[count][..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+            count += 1;
+        }
+        FileSystemResult::Ok((count, files))
+    }
+
+    // A snapshot is a root block, listed in the snapshot table, holding the snapshot's
+    // name and creation time and pointing at copies of the inode bitmap and inode
+    // table as they were. Every block those inodes point at gains a reference, so the
+    // live file system copies it before changing it. The first byte of the snapshot
+    // table names the slot being rolled back to, plus one, while a rollback is under
+    // way; then come each slot's root block, or 0, and the snapshots being taken and
+    // deleted.
+
+    /// Whether changes are refused: the disk is mounted read-only or a snapshot is.
+    fn read_only(&self) -> bool {
+        self.mounted_read_only || self.snapshot_view.is_some()
+    }
+
+    fn check_writable(&self) -> FileSystemResult<()> {
+        if self.read_only() {
+            FileSystemResult::Err(FileSystemError::ReadOnly)
+        } else {
+            FileSystemResult::Ok(())
+        }
+    }
+
+    fn check_none_open(&self) -> FileSystemResult<()> {
+        if self.open_inodes.contains(&true) {
+            FileSystemResult::Err(FileSystemError::FilesOpen)
+        } else {
+            FileSystemResult::Ok(())
+        }
+    }
+
+    /// Where metadata block `block` is read from: its home, or the mounted snapshot's
+    /// copy of it for the inode bitmap and inode table.
+    fn view_location(&mut self, block: usize) -> FileSystemResult<usize> {
+        let copy = match block {
+            INODE_FULL_BLOCK => 0,
+            _ if (INODE_TABLE_START..self.journal_start()).contains(&block) => {
+                1 + block - INODE_TABLE_START
+            }
+            _ => return FileSystemResult::Ok(block),
+        };
+        let root = match self.snapshot_view {
+            Some(root) => root,
+            None => return FileSystemResult::Ok(block),
+        };
+        let mut buffer = [0; BLOCK_SIZE];
+        fs_try!(self.read_data(root as usize, &mut buffer));
+        FileSystemResult::Ok(buffer[SNAPSHOT_HEADER_BYTES + copy] as usize)
+    }
+
+    /// Offset of the name in a snapshot root, after the copy pointers.
+    fn snapshot_name_start(&self) -> usize {
+        SNAPSHOT_HEADER_BYTES + 1 + self.num_inode_blocks()
+    }
+
+    fn read_snapshot_table(&mut self) -> FileSystemResult<[u8; BLOCK_SIZE]> {
+        let mut table = [0; BLOCK_SIZE];
+        fs_try!(self.read_meta(self.snapshot_table_block(), &mut table));
+        FileSystemResult::Ok(table)
+    }
+
+    /// Finds the table slot of the snapshot called `name`.
+    fn find_snapshot(&mut self, name: &str) -> FileSystemResult<Option<usize>> {
+        let table = fs_try!(self.read_snapshot_table());
+        let mut root = [0; BLOCK_SIZE];
+        for slot in 0..MAX_SNAPSHOTS {
+            if table[1 + slot] == 0 {
+                continue;
+            }
+            fs_try!(self.read_data(table[1 + slot] as usize, &mut root));
+            let start = self.snapshot_name_start();
+            if root.get(start..start + root[0] as usize) == Some(name.as_bytes()) {
+                return FileSystemResult::Ok(Some(slot));
+            }
+        }
+        FileSystemResult::Ok(None)
+    }
+
+    /// The inode `inode_num` as the snapshot with root `root` has it, if allocated there.
+    fn snapshot_inode(
+        &mut self,
+        root: &[u8; BLOCK_SIZE],
+        inode_num: usize,
+    ) -> FileSystemResult<Option<Inode<MAX_FILE_BLOCKS>>> {
+        let mut buffer = [0; BLOCK_SIZE];
+        fs_try!(self.read_data(root[SNAPSHOT_HEADER_BYTES] as usize, &mut buffer));
+        if buffer[inode_num / 8] & (1 << (inode_num % 8)) == 0 {
+            return FileSystemResult::Ok(None);
+        }
+        let (block, start) = Self::GEOMETRY.inode_location(inode_num);
+        let copy = root[SNAPSHOT_HEADER_BYTES + 1 + block - INODE_TABLE_START];
+        fs_try!(self.read_data(copy as usize, &mut buffer));
+        FileSystemResult::Ok(Some(Inode::from_bytes(
+            &buffer[start..start + self.num_inode_bytes()],
+        )))
+    }
+
+    /// Adds one reference to every block the inode points at.
+    fn share_pointers(&mut self, inode: &Inode<MAX_FILE_BLOCKS>) -> FileSystemResult<()> {
+        for block in inode.pointers() {
+            let refs = fs_try!(self.block_refs(block));
+            fs_try!(self.set_block_refs(block, refs.saturating_add(1)));
+        }
+        FileSystemResult::Ok(())
+    }
+
+    /// Records the current state of the whole file system as the snapshot `name`.
+    /// Only the inode bitmap and table are copied; file data, the directory and
+    /// attributes are shared with the live file system until it changes them.
+    /// However many blocks that takes, it's committed in pieces that fit the journal;
+    /// one cut short by a crash or an error is finished on the next mount or call.
+    pub fn snapshot_create(&mut self, name: &str) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        fs_try!(self.check_filename(name));
+        fs_try!(self.finish_snapshot());
+        if fs_try!(self.find_snapshot(name)).is_some() {
+            return FileSystemResult::Err(FileSystemError::SnapshotExists);
+        }
+        let table = fs_try!(self.read_snapshot_table());
+        if !table[1..=MAX_SNAPSHOTS].contains(&0) {
+            return FileSystemResult::Err(FileSystemError::TooManySnapshots);
+        }
+        if fs_try!(self.num_free_blocks()) < 2 + self.num_inode_blocks() {
+            return FileSystemResult::Err(FileSystemError::DiskFull);
+        }
+        let bitmap = fs_try!(self.snapshot_bitmap());
+        for inode_num in (0..MAX_FILES_STORED).filter(|i| bitmap[i / 8] & (1 << (i % 8)) != 0) {
+            let inode = fs_try!(self.read_inode(inode_num));
+            for block in inode.pointers() {
+                if fs_try!(self.block_refs(block)) == u8::MAX {
+                    return FileSystemResult::Err(FileSystemError::TooManyLinks);
+                }
+            }
+        }
+        // Only the snapshot's own changes may be dropped if it fails.
+        fs_try!(self.commit_transaction());
+        let result = self.start_snapshot(name);
+        fs_try!(self.commit_or_abort(result));
+        self.finish_snapshot()
+    }
+
+    /// The live inode bitmap without the inodes deleted while open. They're freed on
+    /// closing, so snapshots leave them out.
+    fn snapshot_bitmap(&mut self) -> FileSystemResult<[u8; BLOCK_SIZE]> {
+        let mut bitmap = [0; BLOCK_SIZE];
+        fs_try!(self.read_meta(INODE_FULL_BLOCK, &mut bitmap));
+        for inode_num in 0..MAX_FILES_STORED {
+            if bitmap[inode_num / 8] & (1 << (inode_num % 8)) != 0
+                && fs_try!(self.read_inode(inode_num)).links == 0
+            {
+                bitmap[inode_num / 8] &= !(1 << (inode_num % 8));
+            }
+        }
+        FileSystemResult::Ok(bitmap)
+    }
+
+    /// Records in the snapshot table that a snapshot called `name` is being taken,
+    /// with a root that doesn't point at any copies yet.
+    fn start_snapshot(&mut self, name: &str) -> FileSystemResult<()> {
+        let root_block = fs_try!(self.alloc_block());
+        let mut root = [0; BLOCK_SIZE];
+        root[0] = name.len() as u8;
+        root[1..SNAPSHOT_HEADER_BYTES].copy_from_slice(&self.clock.now().to_be_bytes());
+        let start = self.snapshot_name_start();
+        root[start..start + name.len()].copy_from_slice(name.as_bytes());
+        self.write_meta(root_block as usize, &root);
+        let mut table = fs_try!(self.read_snapshot_table());
+        table[SNAPSHOT_PENDING] = root_block;
+        self.write_meta(self.snapshot_table_block(), &table);
+        FileSystemResult::Ok(())
+    }
+
+    /// Carries out the snapshot the snapshot table says is being taken, if any, and
+    /// lists it in the first free slot.
+    fn finish_snapshot(&mut self) -> FileSystemResult<()> {
+        let result = self.continue_snapshot();
+        self.commit_or_abort(result)
+    }
+
+    /// Copying the inode table and adding a reference to every block it points at can
+    /// stage more blocks than the journal holds, so it's done a step at a time. Each
+    /// step records its progress, in the root or the table, and what's staged is
+    /// committed whenever the next step might not fit. A snapshot cut short by a crash
+    /// is finished on the next mount.
+    fn continue_snapshot(&mut self) -> FileSystemResult<()> {
+        let mut table = fs_try!(self.read_snapshot_table());
+        let root_block = table[SNAPSHOT_PENDING] as usize;
+        if root_block == 0 {
+            return FileSystemResult::Ok(());
+        }
+        let mut root = [0; BLOCK_SIZE];
+        fs_try!(self.read_meta(root_block, &mut root));
+        let mut buffer = [0; BLOCK_SIZE];
+        for i in 0..=self.num_inode_blocks() {
+            if root[SNAPSHOT_HEADER_BYTES + i] != 0 {
+                continue;
+            }
+            fs_try!(self.reserve_journal(JOURNAL_STEP_BLOCKS));
+            if i == 0 {
+                buffer = fs_try!(self.snapshot_bitmap());
+            } else {
+                fs_try!(self.read_meta(INODE_TABLE_START + i - 1, &mut buffer));
+            }
+            let copy = fs_try!(self.alloc_block());
+            self.write_data(copy as usize, &buffer);
+            root[SNAPSHOT_HEADER_BYTES + i] = copy;
+            self.write_meta(root_block, &root);
+        }
+
+        let shared = Self::snapshot_progress(&table, SNAPSHOT_PENDING);
+        let mut pointer = 0;
+        for inode_num in 0..MAX_FILES_STORED {
+            let inode = match fs_try!(self.snapshot_inode(&root, inode_num)) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            for block in inode.pointers() {
+                pointer += 1;
+                if pointer <= shared {
+                    continue;
+                }
+                fs_try!(self.reserve_journal(JOURNAL_STEP_BLOCKS));
+                let refs = fs_try!(self.block_refs(block));
+                fs_try!(self.set_block_refs(block, refs.saturating_add(1)));
+                Self::set_snapshot_progress(&mut table, SNAPSHOT_PENDING, pointer);
+                self.write_meta(self.snapshot_table_block(), &table);
+            }
+        }
+
+        let slot = match table[1..=MAX_SNAPSHOTS].iter().position(|root| *root == 0) {
+            Some(slot) => slot,
+            None => return FileSystemResult::Err(FileSystemError::TooManySnapshots),
+        };
+        table[1 + slot] = root_block as u8;
+        table[SNAPSHOT_PENDING..SNAPSHOT_PENDING + 3].fill(0);
+        self.write_meta(self.snapshot_table_block(), &table);
+        FileSystemResult::Ok(())
+    }
+
+    /// How many block pointers the snapshot being taken or deleted, `SNAPSHOT_PENDING`
+    /// or `SNAPSHOT_DROPPING`, has gained or given up a reference through so far.
+    fn snapshot_progress(table: &[u8; BLOCK_SIZE], field: usize) -> usize {
+        u16::from_be_bytes([table[field + 1], table[field + 2]]) as usize
+    }
+
+    fn set_snapshot_progress(table: &mut [u8; BLOCK_SIZE], field: usize, pointers: usize) {
+        table[field + 1..field + 3].copy_from_slice(&(pointers as u16).to_be_bytes());
+    }
+
+    /// Lists the snapshots in table order.
+    pub fn snapshot_list(
+        &mut self,
+    ) -> FileSystemResult<[Option<SnapshotInfo<MAX_FILENAME_BYTES>>; MAX_SNAPSHOTS]> {
+        let table = fs_try!(self.read_snapshot_table());
+        let mut snapshots = [None; MAX_SNAPSHOTS];
+        let mut root = [0; BLOCK_SIZE];
+        for (slot, snapshot) in snapshots.iter_mut().enumerate() {
+            if table[1 + slot] == 0 {
+                continue;
+            }
+            fs_try!(self.read_data(table[1 + slot] as usize, &mut root));
+            let start = self.snapshot_name_start();
+            let name_len = (root[0] as usize).min(MAX_FILENAME_BYTES);
+            let mut name = [0; MAX_FILENAME_BYTES];
+            name[..name_len].copy_from_slice(&root[start..start + name_len]);
+            *snapshot = Some(SnapshotInfo {
+                name,
+                name_len,
+                created: u32::from_be_bytes(root[1..SNAPSHOT_HEADER_BYTES].try_into().unwrap()),
+            });
+        }
+        FileSystemResult::Ok(snapshots)
+    }
+
+    /// Deletes the snapshot `name`, freeing whatever only it still refers to. Like
+    /// taking a snapshot, that's committed in pieces that fit the journal, and a
+    /// deletion cut short is finished on the next mount or call.
+    pub fn snapshot_delete(&mut self, name: &str) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        fs_try!(self.finish_snapshot_delete());
+        let slot = match fs_try!(self.find_snapshot(name)) {
+            Some(slot) => slot,
+            None => return FileSystemResult::Err(FileSystemError::SnapshotNotFound),
+        };
+        fs_try!(self.commit_transaction());
+        let mut table = fs_try!(self.read_snapshot_table());
+        table[SNAPSHOT_DROPPING] = table[1 + slot];
+        table[1 + slot] = 0;
+        self.write_meta(self.snapshot_table_block(), &table);
+        fs_try!(self.commit_transaction());
+        self.finish_snapshot_delete()
+    }
+
+    /// Frees the snapshot the snapshot table says is being deleted, if any.
+    fn finish_snapshot_delete(&mut self) -> FileSystemResult<()> {
+        let result = self.continue_snapshot_delete();
+        self.commit_or_abort(result)
+    }
+
+    /// Gives up the snapshot's claim on each block its inodes point at, a step at a
+    /// time as in `continue_snapshot`, then frees its root and copies.
+    fn continue_snapshot_delete(&mut self) -> FileSystemResult<()> {
+        let mut table = fs_try!(self.read_snapshot_table());
+        let root_block = table[SNAPSHOT_DROPPING];
+        if root_block == 0 {
+            return FileSystemResult::Ok(());
+        }
+        let mut root = [0; BLOCK_SIZE];
+        fs_try!(self.read_data(root_block as usize, &mut root));
+        let released = Self::snapshot_progress(&table, SNAPSHOT_DROPPING);
+        let mut pointer = 0;
+        for inode_num in 0..MAX_FILES_STORED {
+            let inode = match fs_try!(self.snapshot_inode(&root, inode_num)) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            for block in inode.pointers() {
+                pointer += 1;
+                if pointer <= released {
+                    continue;
+                }
+                fs_try!(self.reserve_journal(JOURNAL_STEP_BLOCKS));
+                fs_try!(self.release_block(block));
+                Self::set_snapshot_progress(&mut table, SNAPSHOT_DROPPING, pointer);
+                self.write_meta(self.snapshot_table_block(), &table);
+            }
+        }
+
+        fs_try!(self.reserve_journal(JOURNAL_STEP_BLOCKS));
+        for copy in &root[SNAPSHOT_HEADER_BYTES..self.snapshot_name_start()] {
+            fs_try!(self.free_block(*copy));
+        }
+        fs_try!(self.free_block(root_block));
+        table[SNAPSHOT_DROPPING..SNAPSHOT_DROPPING + 3].fill(0);
+        self.write_meta(self.snapshot_table_block(), &table);
+        FileSystemResult::Ok(())
+    }
+
+    /// Puts the whole file system back the way it was when the snapshot `name` was
+    /// taken, discarding every change since. The snapshot itself is kept. No file may
+    /// be open. A rollback cut short by a crash is finished on the next mount. Each
+    /// file is put back in one transaction, so a file too scattered for the journal
+    /// stops the rollback part way with `TransactionTooBig`.
+    pub fn snapshot_rollback(&mut self, name: &str) -> FileSystemResult<()> {
+        fs_try!(self.check_writable());
+        fs_try!(self.check_none_open());
+        let slot = match fs_try!(self.find_snapshot(name)) {
+            Some(slot) => slot,
+            None => return FileSystemResult::Err(FileSystemError::SnapshotNotFound),
+        };
+        let mut table = fs_try!(self.read_snapshot_table());
+        table[0] = slot as u8 + 1;
+        self.write_meta(self.snapshot_table_block(), &table);
+        fs_try!(self.commit_transaction());
+        self.finish_rollback()
+    }
+
+    /// Carries out the rollback the snapshot table says is under way, if any. The
+    /// inode table is too big for one transaction, so each inode is restored in its
+    /// own; one that already matches the snapshot was done before a crash.
+    fn finish_rollback(&mut self) -> FileSystemResult<()> {
+        let mut table = fs_try!(self.read_snapshot_table());
+        if table[0] == 0 {
+            return FileSystemResult::Ok(());
+        }
+        let root_block = table[table[0] as usize];
+        let mut root = [0; BLOCK_SIZE];
+        fs_try!(self.read_data(root_block as usize, &mut root));
+        self.directory.len = None;
+        for inode_num in 0..MAX_FILES_STORED {
+            let live = fs_try!(self.read_inode(inode_num));
+            let restored = fs_try!(self.snapshot_inode(&root, inode_num)).unwrap_or(Inode::new());
+            if live == restored {
+                continue;
+            }
+            // References are added first, so a block both versions share stays put.
+            fs_try!(self.share_pointers(&restored));
+            for block in live.pointers() {
+                fs_try!(self.release_block(block));
+            }
+            fs_try!(self.write_inode(inode_num, &restored));
+            fs_try!(self.commit_transaction());
+        }
+        let mut bitmap = [0; BLOCK_SIZE];
+        fs_try!(self.read_data(root[SNAPSHOT_HEADER_BYTES] as usize, &mut bitmap));
+        self.write_meta(INODE_FULL_BLOCK, &bitmap);
+        table[0] = 0;
+        self.write_meta(self.snapshot_table_block(), &table);
+        self.commit_transaction()
+    }
+
+    /// Mounts the snapshot `name` read-only in place of the live file system, which
+    /// `snapshot_unmount` brings back. No file may be open across either.
+    pub fn snapshot_mount(&mut self, name: &str) -> FileSystemResult<()> {
+        fs_try!(self.check_none_open());
+        let slot = match fs_try!(self.find_snapshot(name)) {
+            Some(slot) => slot,
+            None => return FileSystemResult::Err(FileSystemError::SnapshotNotFound),
+        };
+        let table = fs_try!(self.read_snapshot_table());
+        fs_try!(self.commit_transaction());
+        self.snapshot_view = Some(table[1 + slot]);
+        self.directory.len = None;
+        FileSystemResult::Ok(())
+    }
+
+    pub fn snapshot_unmount(&mut self) -> FileSystemResult<()> {
+        fs_try!(self.check_none_open());
+        self.snapshot_view = None;
+        self.directory.len = None;
+        FileSystemResult::Ok(())
+    }
+
+    /// Cross-checks the bitmaps in blocks 1 and 2, the inode table, the reference
+    /// counts and the directory. With `repair` set, every problem found is also fixed on
+    /// disk: dangling names and orphaned inodes are dropped, link counts, sizes and
+    /// reference counts corrected, a block claimed by more files than its reference
+    /// count allows stays with the first of them, and the data bitmap is rebuilt.
+    /// Snapshots aren't checked, only counted, and nothing is while one is mounted.
+    pub fn fsck(&mut self, repair: bool) -> FileSystemResult<FsckReport> {
+        if repair || self.snapshot_view.is_some() {
+            fs_try!(self.check_writable());
+        }
+        // A repair may touch any number of blocks. Whatever part of it commits leaves
+        // the disk no worse off, so it's committed in pieces as the journal fills.
+        self.split_transactions = repair;
+        let result = self.check_consistency(repair);
+        self.split_transactions = false;
+        result
+    }
+
+    fn check_consistency(&mut self, repair: bool) -> FileSystemResult<FsckReport> {
+        let mut report = FsckReport::default();
+        let mut inode_bitmap = [0; BLOCK_SIZE];
+        let mut data_bitmap = [0; BLOCK_SIZE];
+        fs_try!(self.read_meta(INODE_FULL_BLOCK, &mut inode_bitmap));
+        fs_try!(self.read_meta(DATA_FULL_BLOCK, &mut data_bitmap));
+        let is_set = |bitmap: &[u8; BLOCK_SIZE], i: usize| bitmap[i / 8] & (1 << (i % 8)) != 0;
+
+        let mut names = [0; MAX_FILES_STORED];
+        // Checked as it stands on disk, not as cached.
+        self.directory.len = None;
+        fs_try!(self.load_directory());
+        let allocated = |inode_num: usize| {
+            inode_num != DIRECTORY_INODE
+                && inode_num < MAX_FILES_STORED
+                && is_set(&inode_bitmap, inode_num)
+        };
+        for entry in self.directory.entries() {
+            if allocated(entry.inode) {
+                names[entry.inode] += 1;
+            } else {
+                report.dangling_names += 1;
+            }
+        }
+        if repair {
+            let mut offset = 0;
+            while self.directory.entry_fits(offset) {
+                if allocated(self.directory.entry_inode(offset)) {
+                    offset += self.directory.entry_len(offset);
+                } else {
+                    fs_try!(self.remove_entry(offset));
+                }
+            }
+        }
+
+        // Pointers found to each block. Metadata blocks start out full, so no pointer
+        // into them is ever accepted.
+        let mut referenced = [0; NUM_BLOCKS];
+        referenced[..self.first_data_block()].fill(u8::MAX);
+        let mut refcounts = [0; NUM_BLOCKS];
+        for (i, table) in refcounts.chunks_mut(BLOCK_SIZE).enumerate() {
+            let mut buffer = [0; BLOCK_SIZE];
+            fs_try!(self.read_meta(self.refcount_start() + i, &mut buffer));
+            table.copy_from_slice(&buffer[..table.len()]);
+        }
+        // Snapshots are taken as they are. Their roots and copies belong to them alone,
+        // like metadata, and the blocks their inodes point at are counted first. Of a
+        // snapshot being taken, only the pointers shared so far count, and of one being
+        // deleted, only those not yet released.
+        let snapshots = fs_try!(self.read_snapshot_table());
+        let shared = Self::snapshot_progress(&snapshots, SNAPSHOT_PENDING);
+        let released = Self::snapshot_progress(&snapshots, SNAPSHOT_DROPPING);
+        let roots = snapshots[1..=MAX_SNAPSHOTS]
+            .iter()
+            .map(|root| (*root, 0..usize::MAX))
+            .chain([
+                (snapshots[SNAPSHOT_PENDING], 0..shared),
+                (snapshots[SNAPSHOT_DROPPING], released..usize::MAX),
+            ]);
+        let mut root = [0; BLOCK_SIZE];
+        for (root_block, counted) in roots.filter(|(root, _)| *root != 0) {
+            fs_try!(self.read_data(root_block as usize, &mut root));
+            referenced[root_block as usize] = u8::MAX;
+            for copy in &root[SNAPSHOT_HEADER_BYTES..self.snapshot_name_start()] {
+                referenced[*copy as usize] = u8::MAX;
+            }
+            let mut pointer = 0;
+            for inode_num in 0..MAX_FILES_STORED {
+                if pointer >= counted.end {
+                    break;
+                }
+                if let Some(inode) = fs_try!(self.snapshot_inode(&root, inode_num)) {
+                    for block in inode.pointers() {
+                        if counted.contains(&pointer) {
+                            if let Some(count) = referenced.get_mut(block as usize) {
+                                *count = count.saturating_add(1);
+                            }
+                        }
+                        pointer += 1;
+                    }
+                }
+            }
+        }
+
+        for inode_num in 0..MAX_FILES_STORED {
+            if !is_set(&inode_bitmap, inode_num) {
+                continue;
+            }
+            let original = fs_try!(self.read_inode(inode_num));
+            let mut inode = original;
+            if inode_num != DIRECTORY_INODE {
+                if names[inode_num] == 0 {
+                    if inode.links == 0 && self.open_inodes[inode_num] {
+                        // Deleted while open; `close` frees it.
+                    } else {
+                        report.orphaned_inodes += 1;
+                        if repair {
+                            inode_bitmap[inode_num / 8] &= !(1 << (inode_num % 8));
+                            fs_try!(self.write_inode(inode_num, &Inode::new()));
+                            continue;
+                        }
+                    }
+                } else if inode.links as usize != names[inode_num] {
+                    report.wrong_link_counts += 1;
+                    inode.links = names[inode_num].min(u8::MAX as usize) as u8;
+                }
+            }
+
+            if original.bytes_stored as usize > original.capacity(Self::GEOMETRY) {
+                report.oversized_files += 1;
+            }
+            // A block may be shared by as many inodes as its reference count says.
+            let data_blocks = if inode.inline { 0 } else { MAX_FILE_BLOCKS };
+            let pointers = inode.blocks[..data_blocks]
+                .iter_mut()
+                .chain(core::iter::once(&mut inode.xattr_block));
+            for block in pointers.filter(|b| **b != 0) {
+                let index = *block as usize;
+                let limit = refcounts.get(index).map_or(0, |refs| (*refs).max(1));
+                if index >= NUM_BLOCKS || referenced[index] >= limit {
+                    report.duplicate_blocks += 1;
+                    *block = 0;
+                } else {
+                    referenced[index] += 1;
+                }
+            }
+            inode.bytes_stored = inode
+                .bytes_stored
+                .min(inode.capacity(Self::GEOMETRY) as u16);
+            if repair && inode != original {
+                fs_try!(self.write_inode(inode_num, &inode));
+            }
+        }
+
+        for block in self.first_data_block()..NUM_BLOCKS {
+            if referenced[block] == u8::MAX {
+                continue;
+            }
+            let refs = if referenced[block] > 1 {
+                referenced[block]
+            } else {
+                0
+            };
+            if refcounts[block] != refs {
+                report.wrong_refcounts += 1;
+                if repair {
+                    fs_try!(self.set_block_refs(block as u8, refs));
+                }
+            }
+        }
+        for (block, referenced) in referenced.iter().enumerate() {
+            match (is_set(&data_bitmap, block), *referenced > 0) {
+                (true, false) => {
+                    report.unreferenced_blocks += 1;
+                    self.freed[block] |= repair;
+                }
+                (false, true) => report.unmarked_blocks += 1,
+                _ => continue,
+            }
+            data_bitmap[block / 8] ^= 1 << (block % 8);
+        }
+        if repair {
+            self.write_meta(INODE_FULL_BLOCK, &inode_bitmap);
+            self.write_meta(DATA_FULL_BLOCK, &data_bitmap);
+            fs_try!(self.commit_transaction());
+        }
+        FileSystemResult::Ok(report)
+    }
+
+    /// Packs file data towards the start of the data area, each file's blocks in order,
+    /// so files sit in contiguous runs with the free space after them. Blocks shared
+    /// by reflinks or snapshots stay put, as do directory and attribute blocks.
+    ///
+    /// Each block moves in a transaction of its own and at most `max_work` move per
+    /// call, so it can run a little at a time from a main loop. Returns `true` once
+    /// there's nothing left it can move.
+    pub fn defragment(&mut self, max_work: usize) -> FileSystemResult<bool> {
+        fs_try!(self.check_writable());
+        fs_try!(self.commit_transaction());
+        let mut work = 0;
+        // Everything before the cursor is in place or can't move.
+        let mut cursor = self.first_data_block();
+        for inode_num in 0..MAX_FILES_STORED {
+            if inode_num == DIRECTORY_INODE {
+                continue;
+            }
+            for index in 0..MAX_FILE_BLOCKS {
+                let inode = fs_try!(self.current_inode(inode_num));
+                let block = inode.data_blocks().get(index).copied().unwrap_or(0) as usize;
+                if block == 0 || fs_try!(self.block_refs(block as u8)) > 1 {
+                    continue;
+                }
+                let mut occupant = None;
+                while cursor < block {
+                    if fs_try!(self.find_free_bit(DATA_FULL_BLOCK, cursor, cursor + 1)).is_some() {
+                        break;
+                    }
+                    occupant = fs_try!(self.block_owner(cursor));
+                    if occupant.is_some() {
+                        break;
+                    }
+                    cursor += 1;
+                }
+                if cursor == block {
+                    cursor += 1;
+                }
+                if cursor > block {
+                    continue;
+                }
+                // A later block in the way goes to any free block first.
+                if let Some((owner, owner_index)) = occupant {
+                    if work == max_work {
+                        return FileSystemResult::Ok(false);
+                    }
+                    let first = self.first_data_block();
+                    let spare =
+                        match fs_try!(self.find_free_bit(DATA_FULL_BLOCK, first, NUM_BLOCKS)) {
+                            Some(spare) => spare,
+                            None => return FileSystemResult::Ok(true),
+                        };
+                    fs_try!(self.relocate(owner, owner_index, spare));
+                    work += 1;
+                }
+                if work == max_work {
+                    return FileSystemResult::Ok(false);
+                }
+                fs_try!(self.relocate(inode_num, index, cursor));
+                work += 1;
+                cursor += 1;
+            }
+        }
+        FileSystemResult::Ok(true)
+    }
+
+    /// Writes the live file system, or the snapshot mounted in its place, into the
+    /// empty file system `target`, which may have any geometry. Names, contents with
+    /// their holes, symbolic and hard links, attributes, permissions, owners and
+    /// timestamps are carried over; snapshots aren't. No file may be open on either
+    /// side.
+    ///
+    /// Everything is checked against the target before anything is written to it. If
+    /// something doesn't fit, this fails with the target's error for it, such as
+    /// `TooManyFiles`, `DiskFull`, `FileTooBig`, `FilenameTooLong` or
+    /// `XattrSpaceFull`, and the target is left empty. Only a failure to read the
+    /// source part way through leaves the target part written.
+    pub fn convert_to<
+        const TARGET_OPEN: usize,
+        const TARGET_BLOCK_SIZE: usize,
+        const TARGET_NUM_BLOCKS: usize,
+        const TARGET_FILE_BLOCKS: usize,
+        const TARGET_FILE_BYTES: usize,
+        const TARGET_FILES_STORED: usize,
+        const TARGET_FILENAME_BYTES: usize,
+        TC: Clock,
+        TD: BlockDevice<TARGET_BLOCK_SIZE>,
+    >(
+        &mut self,
+        target: &mut FileSystem<
+            TARGET_OPEN,
+            TARGET_BLOCK_SIZE,
+            TARGET_NUM_BLOCKS,
+            TARGET_FILE_BLOCKS,
+            TARGET_FILE_BYTES,
+            TARGET_FILES_STORED,
+            TARGET_FILENAME_BYTES,
+            TC,
+            TD,
+        >,
+    ) -> FileSystemResult<()> {
+        fs_try!(self.check_none_open());
+        fs_try!(target.check_writable());
+        fs_try!(target.check_none_open());
+        if fs_try!(target.load_directory()) != 0 {
+            return FileSystemResult::Err(FileSystemError::FileExists);
+        }
+        let geometry = Geometry::new(
+            TARGET_BLOCK_SIZE,
+            TARGET_NUM_BLOCKS,
+            TARGET_FILE_BLOCKS,
+            TARGET_FILES_STORED,
+            TARGET_FILENAME_BYTES,
+        );
+        fs_try!(self.check_convert_fits(geometry, fs_try!(target.num_free_blocks())));
+        // The target inode each source inode became, once its first name is in.
+        let mut converted = [None; MAX_FILES_STORED];
+        fs_try!(self.load_directory());
+        let mut offset = 0;
+        while self.directory.entry_fits(offset) {
+            let mut name = [0; MAX_FILENAME_BYTES];
+            let len = self.directory.entry_name(offset).len();
+            name[..len].copy_from_slice(self.directory.entry_name(offset));
+            let src_num = self.directory.entry_inode(offset);
+            offset += self.directory.entry_len(offset);
+            let name = match core::str::from_utf8(&name[..len]) {
+                Ok(name) => name,
+                Err(_) => return FileSystemResult::Err(FileSystemError::InvalidFilename),
+            };
+            fs_try!(target.check_filename(name));
+            match converted.get(src_num).copied() {
+                Some(Some(dst_num)) => {
+                    fs_try!(target.add_entry(name.as_bytes(), dst_num));
+                    let mut inode = fs_try!(target.read_inode(dst_num));
+                    inode.links += 1;
+                    fs_try!(target.write_inode(dst_num, &inode));
+                    fs_try!(target.commit_transaction());
+                }
+                Some(None) => {
+                    converted[src_num] = Some(fs_try!(self.convert_inode(src_num, name, target)))
+                }
+                // Entries a damaged directory can't describe are skipped, as by `read_dir`.
+                None => {}
+            }
+        }
+        let source = fs_try!(self.read_inode(DIRECTORY_INODE));
+        let mut directory = fs_try!(target.read_inode(DIRECTORY_INODE));
+        directory.mode = source.mode;
+        directory.uid = source.uid;
+        directory.gid = source.gid;
+        directory.ctime = source.ctime;
+        directory.mtime = source.mtime;
+        directory.atime = source.atime;
+        fs_try!(target.write_inode(DIRECTORY_INODE, &directory));
+        fs_try!(target.commit_transaction());
+        FileSystemResult::Ok(())
+    }
+
+    /// Checks that `convert_to` can copy everything into an empty file system of
+    /// geometry `target` with `free_blocks` free, failing as the copy would otherwise
+    /// fail part way.
+    fn check_convert_fits(&mut self, target: Geometry, free_blocks: usize) -> FileSystemResult<()> {
+        let mut counted = [false; MAX_FILES_STORED];
+        let (mut names, mut files, mut directory_bytes, mut blocks) = (0, 0, 0, 0);
+        fs_try!(self.load_directory());
+        let mut offset = 0;
+        while self.directory.entry_fits(offset) {
+            let src_num = self.directory.entry_inode(offset);
+            let name_len = self.directory.entry_name(offset).len();
+            fs_try!(
+                match core::str::from_utf8(self.directory.entry_name(offset)) {
+                    Ok(name) => check_filename(name, target.max_filename_bytes),
+                    Err(_) => FileSystemResult::Err(FileSystemError::InvalidFilename),
+                }
+            );
+            offset += self.directory.entry_len(offset);
+            if src_num >= MAX_FILES_STORED {
+                continue;
+            }
+            names += 1;
+            directory_bytes += ENTRY_HEADER_BYTES + name_len;
+            if names > target.max_files_stored || directory_bytes > target.max_file_bytes() {
+                return FileSystemResult::Err(FileSystemError::TooManyFiles);
+            }
+            if counted[src_num] {
+                continue;
+            }
+            counted[src_num] = true;
+            // The target's directory takes an inode too.
+            files += 1;
+            if files >= target.max_files_stored {
+                return FileSystemResult::Err(FileSystemError::TooManyFiles);
+            }
+            let source = fs_try!(self.read_inode(src_num));
+            if source.bytes_stored as usize > target.file_capacity() {
+                return FileSystemResult::Err(FileSystemError::FileTooBig);
+            }
+            let attrs_len = xattr_end(&fs_try!(self.read_xattrs(&source)));
+            if attrs_len > target.block_size {
+                return FileSystemResult::Err(FileSystemError::XattrSpaceFull);
+            }
+            if attrs_len > 0 {
+                blocks += 1;
+            }
+            blocks += self.converted_blocks(&source, target);
+        }
+        blocks += directory_bytes.div_ceil(target.block_size);
+        if blocks > free_blocks {
+            return FileSystemResult::Err(FileSystemError::DiskFull);
+        }
+        FileSystemResult::Ok(())
+    }
+
+    /// Data blocks the copy of `inode` ends up with in a file system of geometry
+    /// `target`, given that `convert_inode` writes the source's blocks in order.
+    fn converted_blocks(&self, inode: &Inode<MAX_FILE_BLOCKS>, target: Geometry) -> usize {
+        let size = inode.bytes_stored as usize;
+        // The copy starts out inline and empty, as `write` sees it.
+        let (mut inline, mut stored, mut blocks, mut next) = (true, 0, 0, 0);
+        let mut count_write = |start: usize, end: usize| {
+            if inline && end <= target.inline_bytes() {
+                stored = stored.max(end);
+                return;
+            }
+            if inline && stored > 0 {
+                blocks += 1;
+                next = 1;
+            }
+            inline = false;
+            let first = (start / target.block_size).max(next);
+            let last = end.div_ceil(target.block_size);
+            if last > first {
+                blocks += last - first;
+                next = last;
+            }
+        };
+        if inode.inline {
+            if size > 0 {
+                count_write(0, size);
+            }
+        } else {
+            for (index, block) in inode.data_blocks().iter().enumerate() {
+                let start = index * BLOCK_SIZE;
+                if *block != 0 && start < size {
+                    count_write(start, size.min(start + BLOCK_SIZE));
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Recreates inode `src_num` in `target` under `name` and returns its number there.
+    fn convert_inode<
+        const TARGET_OPEN: usize,
+        const TARGET_BLOCK_SIZE: usize,
+        const TARGET_NUM_BLOCKS: usize,
+        const TARGET_FILE_BLOCKS: usize,
+        const TARGET_FILE_BYTES: usize,
+        const TARGET_FILES_STORED: usize,
+        const TARGET_FILENAME_BYTES: usize,
+        TC: Clock,
+        TD: BlockDevice<TARGET_BLOCK_SIZE>,
+    >(
+        &mut self,
+        src_num: usize,
+        name: &str,
+        target: &mut FileSystem<
+            TARGET_OPEN,
+            TARGET_BLOCK_SIZE,
+            TARGET_NUM_BLOCKS,
+            TARGET_FILE_BLOCKS,
+            TARGET_FILE_BYTES,
+            TARGET_FILES_STORED,
+            TARGET_FILENAME_BYTES,
+            TC,
+            TD,
+        >,
+    ) -> FileSystemResult<usize> {
+        let source = fs_try!(self.read_inode(src_num));
+        let size = source.bytes_stored as usize;
+        if size > target.file_capacity() {
+            return FileSystemResult::Err(FileSystemError::FileTooBig);
+        }
+        let attrs = fs_try!(self.read_xattrs(&source));
+        let attrs_len = xattr_end(&attrs);
+        if attrs_len > TARGET_BLOCK_SIZE {
+            return FileSystemResult::Err(FileSystemError::XattrSpaceFull);
+        }
+        let dst_num =
+            fs_try!(target.create_inode(name.as_bytes(), source.kind, Credentials::ROOT, 0));
+        fs_try!(target.commit_transaction());
+
+        // Only the blocks the source has are written, so its holes stay holes.
+        let fd = fs_try!(target.open_inode(dst_num, Credentials::ROOT, false, true, false));
+        let mut copy_contents = || {
+            if source.inline {
+                return target.write(fd, &source.blocks[..size]);
+            }
+            let mut buffer = [0; BLOCK_SIZE];
+            for (index, block) in source.data_blocks().iter().enumerate() {
+                let start = index * BLOCK_SIZE;
+                if *block != 0 && start < size {
+                    fs_try!(self.read_data(*block as usize, &mut buffer));
+                    fs_try!(target.seek(fd, start));
+                    fs_try!(target.write(fd, &buffer[..(size - start).min(BLOCK_SIZE)]));
+                }
+            }
+            FileSystemResult::Ok(())
+        };
+        let result = copy_contents();
+        fs_try!(target.close(fd));
+        fs_try!(result);
+
+        let mut target_attrs = [0; TARGET_BLOCK_SIZE];
+        target_attrs[..attrs_len].copy_from_slice(&attrs[..attrs_len]);
+        fs_try!(target.write_xattrs(dst_num, &target_attrs));
+        let mut inode = fs_try!(target.read_inode(dst_num));
+        inode.bytes_stored = source.bytes_stored;
+        inode.mode = source.mode;
+        inode.uid = source.uid;
+        inode.gid = source.gid;
+        inode.ctime = source.ctime;
+        inode.mtime = source.mtime;
+        inode.atime = source.atime;
+        fs_try!(target.write_inode(dst_num, &inode));
+        fs_try!(target.commit_transaction());
+        FileSystemResult::Ok(dst_num)
+    }
+
+    /// Finds the file data pointer to `block`, as an inode and block index, if it's
+    /// the block's only reference.
+    fn block_owner(&mut self, block: usize) -> FileSystemResult<Option<(usize, usize)>> {
+        if fs_try!(self.block_refs(block as u8)) > 1 {
+            return FileSystemResult::Ok(None);
+        }
+        for inode_num in 0..MAX_FILES_STORED {
+            if inode_num == DIRECTORY_INODE {
+                continue;
+            }
+            let inode = fs_try!(self.current_inode(inode_num));
+            if let Some(index) = inode
+                .data_blocks()
+                .iter()
+                .position(|b| *b as usize == block)
+            {
+                return FileSystemResult::Ok(Some((inode_num, index)));
+            }
+        }
+        FileSystemResult::Ok(None)
+    }
+
+    /// Moves data block `index` of `inode_num` to the free block `to`. It commits
+    /// straight away, as the old block may be the next one written over.
+    fn relocate(&mut self, inode_num: usize, index: usize, to: usize) -> FileSystemResult<()> {
+        let result = self.move_block(inode_num, index, to);
+        self.commit_or_abort(result)
+    }
+
+    fn move_block(&mut self, inode_num: usize, index: usize, to: usize) -> FileSystemResult<()> {
+        let mut inode = fs_try!(self.current_inode(inode_num));
+        let from = inode.blocks[index];
+        let mut buffer = [0; BLOCK_SIZE];
+        fs_try!(self.read_data(from as usize, &mut buffer));
+        fs_try!(self.set_bit(DATA_FULL_BLOCK, to, true));
+        self.freed[to] = false;
+        self.write_data(to, &buffer);
+        fs_try!(self.free_block(from));
+        inode.blocks[index] = to as u8;
+        self.write_inode(inode_num, &inode)
+    }
+}
+
+impl<
+        const MAX_OPEN: usize,
+        const BLOCK_SIZE: usize,
+        const NUM_BLOCKS: usize,
+        const MAX_FILE_BLOCKS: usize,
+        const MAX_FILE_BYTES: usize,
+        const MAX_FILES_STORED: usize,
+        const MAX_FILENAME_BYTES: usize,
+        C: Clock,
+        D: BlockDevice<BLOCK_SIZE>,
+    > Layout<MAX_FILE_BLOCKS>
+    for FileSystem<
+        MAX_OPEN,
+        BLOCK_SIZE,
+        NUM_BLOCKS,
+        MAX_FILE_BLOCKS,
+        MAX_FILE_BYTES,
+        MAX_FILES_STORED,
+        MAX_FILENAME_BYTES,
+        C,
+        D,
+    >
+{
+    type Buffer = [u8; MAX_FILE_BYTES];
+    type Index = [u16; MAX_FILE_BYTES];
+
+    fn geometry(&self) -> Geometry {
+        Self::GEOMETRY
+    }
+
+    fn now(&mut self) -> u32 {
+        self.clock.now()
+    }
+
+    fn directory(&self) -> &Directory<Self::Buffer, Self::Index> {
+        &self.directory
+    }
+
+    fn directory_mut(&mut self) -> &mut Directory<Self::Buffer, Self::Index> {
+        &mut self.directory
+    }
+
+    fn read_meta_with<T: Copy>(
+        &mut self,
+        block: usize,
+        read: impl FnOnce(&[u8]) -> T,
+    ) -> FileSystemResult<T> {
+        let mut buffer = [0; BLOCK_SIZE];
+        fs_try!(self.read_meta(block, &mut buffer));
+        FileSystemResult::Ok(read(&buffer))
+    }
+
+    fn modify_meta(
+        &mut self,
+        block: usize,
+        change: impl FnOnce(&mut [u8]),
+    ) -> FileSystemResult<()> {
+        let mut buffer = [0; BLOCK_SIZE];
+        fs_try!(self.read_meta(block, &mut buffer));
+        change(&mut buffer);
+        self.write_meta(block, &buffer);
+        FileSystemResult::Ok(())
+    }
+
+    fn read_data_with<T: Copy>(
+        &mut self,
+        block: usize,
+        read: impl FnOnce(&[u8]) -> T,
+    ) -> FileSystemResult<T> {
+        let mut buffer = [0; BLOCK_SIZE];
+        fs_try!(self.read_data(block, &mut buffer));
+        FileSystemResult::Ok(read(&buffer))
+    }
+
+    fn load_directory_block(&mut self, index: usize, block: u8) -> FileSystemResult<()> {
+        let mut buffer = [0; BLOCK_SIZE];
+        if block != 0 {
+            fs_try!(self.read_meta(block as usize, &mut buffer));
+        }
+        self.directory.buffer[index * BLOCK_SIZE..(index + 1) * BLOCK_SIZE]
+            .copy_from_slice(&buffer);
+        FileSystemResult::Ok(())
+    }
+
+    fn save_directory_block(&mut self, index: usize, block: u8) {
+        let mut buffer = [0; BLOCK_SIZE];
+        buffer
+            .copy_from_slice(&self.directory.buffer[index * BLOCK_SIZE..(index + 1) * BLOCK_SIZE]);
+        self.write_meta(block as usize, &buffer);
+    }
+
+    fn block_allocated(&mut self, block: u8) {
+        // Freed earlier in the transaction, it's about to be written again.
+        self.freed[block as usize] = false;
+    }
+
+    fn block_freed(&mut self, block: u8) {
+        // A staged copy must not land on the block after it's reused for file data.
+        // The stale checksum is left alone: the next owner writes the block, and so
+        // its checksum, before reading it, and freeing stays within the journal.
+        self.unstage(block as usize);
+        self.freed[block as usize] = true;
+    }
+
+    /// Refreshes any descriptor that has the inode open.
+    fn inode_written(&mut self, inode_num: usize, inode: &Inode<MAX_FILE_BLOCKS>) {
+        for file in self.open.iter_mut().flatten() {
+            if file.inode_num == inode_num {
+                file.inode = *inode;
+            }
+        }
+    }
+
+    fn is_open(&self, inode_num: usize) -> bool {
+        self.open_inodes[inode_num]
+    }
+
+    /// Prefers the copy held by a descriptor that has the inode open.
+    fn current_inode(&mut self, inode_num: usize) -> FileSystemResult<Inode<MAX_FILE_BLOCKS>> {
+        match self
+            .open
+            .iter()
+            .flatten()
+            .find(|file| file.inode_num == inode_num)
+        {
+            Some(file) => FileSystemResult::Ok(file.inode),
+            None => self.read_inode(inode_num),
+        }
+    }
+}
+
+/// Pointers a `DynFileSystem` inode has room for. A valid geometry fits an inode in a
+/// block and a file in a `u16`, which keeps `max_file_blocks` well below this.
+#[cfg(feature = "alloc")]
+const DYN_MAX_FILE_BLOCKS: usize = u8::MAX as usize;
+
+/// A file system whose geometry is read from the superblock at runtime rather than
+/// fixed by type parameters, for tools that handle images of any shape. It works on
+/// a whole image held in memory, laid out exactly as a `FileSystem` lays out its
+/// disk, so the image can be handed to one afterwards.
+///
+/// Every operation acts as the superuser. Each one either completes or leaves the
+/// image as it was. Snapshots are left alone, though blocks shared with them are
+/// copied before they're changed, as `FileSystem` does. `FileSystemResult` only holds
+/// `Copy` values, so results here are plain `Result`s.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone)]
+pub struct DynFileSystem<C: Clock = CounterClock> {
+    geometry: Geometry,
+    /// Every block of the disk, one after another.
+    image: alloc::vec::Vec<u8>,
+    directory: Directory<alloc::vec::Vec<u8>, alloc::vec::Vec<u16>>,
+    /// Type and size of each inode the directory names, filled in by `read_dir` for
+    /// its iterator to look up.
+    listed_inodes: alloc::vec::Vec<Option<(FileType, usize)>>,
+    clock: C,
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Clock> DynFileSystem<C> {
+    /// Creates an empty file system laid out with `geometry`.
+    pub fn format(geometry: Geometry) -> Result<Self, FileSystemError>
+    where
+        C: Default,
+    {
+        Self::format_with_clock(geometry, C::default())
+    }
+
+    pub fn format_with_clock(geometry: Geometry, clock: C) -> Result<Self, FileSystemError> {
+        if geometry.check().is_err() {
+            return Err(FileSystemError::InvalidGeometry);
+        }
+        let image = alloc::vec![0; geometry.block_size * geometry.num_blocks];
+        let mut result = Self::with_image(geometry, image, clock);
+        Result::from(result.write_empty_layout())?;
+        Result::from(result.load_directory())?;
+        Ok(result)
+    }
+
+    /// Opens an image written by a `FileSystem` or a `DynFileSystem` of any geometry,
+    /// finishing a transaction its journal holds. A snapshot rollback cut short is
+    /// left for the next `FileSystem` mount to finish.
+    pub fn open(image: alloc::vec::Vec<u8>) -> Result<Self, FileSystemError>
+    where
+        C: Default,
+    {
+        Self::open_with_clock(image, C::default())
+    }
+
+    pub fn open_with_clock(image: alloc::vec::Vec<u8>, clock: C) -> Result<Self, FileSystemError> {
+        let geometry = match Geometry::from_superblock(&image) {
+            Some(geometry) if geometry.check().is_ok() => geometry,
+            _ => return Err(FileSystemError::InvalidGeometry),
+        };
+        if image.len() != geometry.block_size * geometry.num_blocks {
+            return Err(FileSystemError::InvalidGeometry);
+        }
+        let mut result = Self::with_image(geometry, image, clock);
+        result.replay_journal();
+        Result::from(result.load_directory())?;
+        Ok(result)
+    }
+
+    fn with_image(geometry: Geometry, image: alloc::vec::Vec<u8>, clock: C) -> Self {
+        Self {
+            geometry,
+            image,
+            directory: Directory {
+                buffer: alloc::vec![0; geometry.max_file_bytes()],
+                len: None,
+                index: alloc::vec![0; geometry.max_file_bytes()],
+            },
+            listed_inodes: alloc::vec![None; geometry.max_files_stored],
+            clock,
+        }
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    pub fn into_image(self) -> alloc::vec::Vec<u8> {
+        self.image
+    }
+
+    /// Copies a committed transaction from the journal to its home blocks, as
+    /// `FileSystem::replay_journal` does, and clears the journal.
+    fn replay_journal(&mut self) {
+        let start = self.geometry.journal_start();
+        let header = self.raw_block(start).to_vec();
+        if header[0] != JOURNAL_COMMITTED {
+            return;
+        }
+        let len = (header[1] as usize).min(JOURNAL_CAPACITY);
+        let copies: alloc::vec::Vec<alloc::vec::Vec<u8>> = (0..len)
+            .map(|slot| self.raw_block(start + 1 + slot).to_vec())
+            .collect();
+        if journal_intact(self.geometry, &header, &copies) {
+            let targets = &header[JOURNAL_HEADER_BYTES..JOURNAL_HEADER_BYTES + len];
+            for (target, copy) in targets.iter().zip(&copies) {
+                self.raw_block_mut(*target as usize).copy_from_slice(copy);
+            }
+        }
+        self.raw_block_mut(start).fill(0);
+    }
+
+    /// Runs `op`, putting the image back as it was if it fails.
+    fn transaction<T: Copy>(
+        &mut self,
+        op: impl FnOnce(&mut Self) -> FileSystemResult<T>,
+    ) -> Result<T, FileSystemError> {
+        let image = self.image.clone();
+        let directory = self.directory.clone();
+        let result = Result::from(op(self));
+        if result.is_err() {
+            self.image = image;
+            self.directory = directory;
+        }
+        result
+    }
+
+    // Blocks are checked against the checksum table as they're read, and their
+    // checksums updated as they're written, just as `FileSystem` does it.
+
+    fn raw_block(&self, block: usize) -> &[u8] {
+        let size = self.geometry.block_size;
+        &self.image[block * size..(block + 1) * size]
+    }
+
+    fn raw_block_mut(&mut self, block: usize) -> &mut [u8] {
+        let size = self.geometry.block_size;
+        &mut self.image[block * size..(block + 1) * size]
+    }
+
+    /// The table is contiguous, so a block's checksum is at a fixed place in the image.
+    fn checksum_offset(&self, block: usize) -> usize {
+        let (table_block, start) = self.geometry.checksum_location(block);
+        table_block * self.geometry.block_size + start
+    }
+
+    /// Block `block`, checked against its stored checksum.
+    fn block(&self, block: usize) -> FileSystemResult<&[u8]> {
+        if block >= self.geometry.num_blocks {
+            return FileSystemResult::Err(FileSystemError::Corrupted { block });
+        }
+        let at = self.checksum_offset(block);
+        let expected = u32::from_be_bytes(self.image[at..at + 4].try_into().unwrap());
+        let contents = self.raw_block(block);
+        if expected != 0 && expected != block_checksum(contents) {
+            return FileSystemResult::Err(FileSystemError::Corrupted { block });
+        }
+        FileSystemResult::Ok(contents)
+    }
+
+    fn write_block(&mut self, block: usize, contents: &[u8]) {
+        self.raw_block_mut(block).copy_from_slice(contents);
+        let at = self.checksum_offset(block);
+        self.image[at..at + 4].copy_from_slice(&block_checksum(contents).to_be_bytes());
+    }
+
+    /// Lists the directory in the order names were added, skipping entries a damaged
+    /// directory can't describe, as `FileSystem::read_dir` does.
+    pub fn read_dir(&mut self) -> Result<ReadDir<'_>, FileSystemError> {
+        let mut listed = core::mem::take(&mut self.listed_inodes);
+        let result = self.list_inodes(&mut listed);
+        self.listed_inodes = listed;
+        Result::from(result)?;
+        Ok(ReadDir {
+            entries: self.directory.entries(),
+            inodes: &self.listed_inodes,
+        })
+    }
+
+    /// Describes the file `filename` names, following symbolic links.
+    pub fn stat(&mut self, filename: &str) -> Result<Stat, FileSystemError> {
+        Result::from(self.stat_file(filename))
+    }
+
+    /// The contents of the file `filename` names, following symbolic links.
+    pub fn read_file(&mut self, filename: &str) -> Result<alloc::vec::Vec<u8>, FileSystemError> {
+        let inode_num = Result::from(self.lookup(filename))?;
+        let inode = Result::from(self.read_inode(inode_num))?;
+        let mut contents = alloc::vec![0; inode.bytes_stored as usize];
+        Result::from(self.read_inode_data(&inode, &mut contents))?;
+        Ok(contents)
+    }
+
+    /// Replaces the contents of `filename`, creating it if need be. Contents that fit
+    /// are stored inline, and blocks of zeros are left as holes. Takes as many bytes
+    /// as a `FileSystem` of the same geometry lets a file hold.
+    pub fn write_file(&mut self, filename: &str, contents: &[u8]) -> Result<(), FileSystemError> {
+        if contents.len() > self.geometry.file_capacity() {
+            return Err(FileSystemError::FileTooBig);
+        }
+        self.transaction(|sys| {
+            let mut name = alloc::vec![0; sys.geometry.max_filename_bytes];
+            let inode_num = match fs_try!(sys.resolve(filename, &mut name)) {
+                (_, Some(inode_num)) => inode_num,
+                (len, None) => {
+                    fs_try!(sys.create_inode(&name[..len], FileType::File, Credentials::ROOT, 0))
+                }
+            };
+            let mut inode = fs_try!(sys.read_inode(inode_num));
+            for block in inode.data_blocks() {
+                if *block != 0 {
+                    fs_try!(sys.release_block(*block));
+                }
+            }
+            inode.blocks = [0; DYN_MAX_FILE_BLOCKS];
+            inode.inline = contents.len() <= sys.geometry.inline_bytes();
+            if inode.inline {
+                inode.blocks[..contents.len()].copy_from_slice(contents);
+            } else {
+                let mut buffer = alloc::vec![0; sys.geometry.block_size];
+                for (i, chunk) in contents.chunks(buffer.len()).enumerate() {
+                    if chunk.iter().all(|byte| *byte == 0) {
+                        continue;
+                    }
+                    let block = fs_try!(sys.alloc_block());
+                    buffer.fill(0);
+                    buffer[..chunk.len()].copy_from_slice(chunk);
+                    sys.write_block(block as usize, &buffer);
+                    inode.blocks[i] = block;
+                }
+            }
+            inode.bytes_stored = contents.len() as u16;
+            inode.mtime = sys.clock.now();
+            sys.write_inode(inode_num, &inode)
+        })
+    }
+
+    /// Removes the name `filename`, freeing the file's blocks with its last name.
+    pub fn delete(&mut self, filename: &str) -> Result<(), FileSystemError> {
+        Result::from(check_filename(filename, self.geometry.max_filename_bytes))?;
+        self.transaction(|sys| {
+            fs_try!(sys.load_directory());
+            match sys.directory.find_entry(filename.as_bytes()) {
+                Some(offset) => sys.remove_name(offset),
+                None => FileSystemResult::Err(FileSystemError::FileNotFound),
+            }
+        })
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Clock> Layout<DYN_MAX_FILE_BLOCKS> for DynFileSystem<C> {
+    type Buffer = alloc::vec::Vec<u8>;
+    type Index = alloc::vec::Vec<u16>;
+
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn now(&mut self) -> u32 {
+        self.clock.now()
+    }
+
+    fn directory(&self) -> &Directory<Self::Buffer, Self::Index> {
+        &self.directory
+    }
+
+    fn directory_mut(&mut self) -> &mut Directory<Self::Buffer, Self::Index> {
+        &mut self.directory
+    }
+
+    fn read_meta_with<T: Copy>(
+        &mut self,
+        block: usize,
+        read: impl FnOnce(&[u8]) -> T,
+    ) -> FileSystemResult<T> {
+        FileSystemResult::Ok(read(fs_try!(self.block(block))))
+    }
+
+    fn modify_meta(
+        &mut self,
+        block: usize,
+        change: impl FnOnce(&mut [u8]),
+    ) -> FileSystemResult<()> {
+        let mut contents = fs_try!(self.block(block)).to_vec();
+        change(&mut contents);
+        self.write_block(block, &contents);
+        FileSystemResult::Ok(())
+    }
+
+    fn read_data_with<T: Copy>(
+        &mut self,
+        block: usize,
+        read: impl FnOnce(&[u8]) -> T,
+    ) -> FileSystemResult<T> {
+        self.read_meta_with(block, read)
+    }
+
+    fn load_directory_block(&mut self, index: usize, block: u8) -> FileSystemResult<()> {
+        let size = self.geometry.block_size;
+        let chunk = index * size..(index + 1) * size;
+        if block == 0 {
+            self.directory.buffer[chunk].fill(0);
+        } else {
+            let contents = fs_try!(self.block(block as usize)).to_vec();
+            self.directory.buffer[chunk].copy_from_slice(&contents);
+        }
+        FileSystemResult::Ok(())
+    }
+
+    fn save_directory_block(&mut self, index: usize, block: u8) {
+        let size = self.geometry.block_size;
+        let contents = self.directory.buffer[index * size..(index + 1) * size].to_vec();
+        self.write_block(block as usize, &contents);
+    }
+}
+
+// Helpers shared by `FileSystem` and `DynFileSystem`, which keep their blocks in
+// different places but lay them out the same way.
+
+/// See `FileSystem::check_filename`.
+fn check_filename(filename: &str, max_bytes: usize) -> FileSystemResult<()> {
+    if filename.is_empty() {
+        FileSystemResult::Err(FileSystemError::EmptyFilename)
+    } else if filename.len() > max_bytes {
+        FileSystemResult::Err(FileSystemError::FilenameTooLong)
+    } else if filename.contains(['/', '\0']) {
+        FileSystemResult::Err(FileSystemError::InvalidFilename)
+    } else {
+        FileSystemResult::Ok(())
+    }
+}
+
+fn bit_is_set(bitmap: &[u8], index: usize) -> bool {
+    bitmap[index / 8] & (1 << (index % 8)) != 0
+}
+
+fn set_bit_to(bitmap: &mut [u8], index: usize, used: bool) {
+    if used {
+        bitmap[index / 8] |= 1 << (index % 8);
+    } else {
+        bitmap[index / 8] &= !(1 << (index % 8));
+    }
+}
+
+fn first_clear_bit(bitmap: &[u8], start: usize, end: usize) -> Option<usize> {
+    (start..end).find(|i| !bit_is_set(bitmap, *i))
+}
+
+/// Appends an entry for `name -> inode_num` to the `size` bytes of `directory`,
+/// returning its new size. The caller checks there's room.
+fn push_entry(directory: &mut [u8], size: usize, name: &[u8], inode_num: usize) -> usize {
+    let end = size + ENTRY_HEADER_BYTES + name.len();
+    directory[size] = name.len() as u8;
+    directory[size + 1..size + ENTRY_HEADER_BYTES]
+        .copy_from_slice(&(inode_num as u16).to_be_bytes());
+    directory[size + ENTRY_HEADER_BYTES..end].copy_from_slice(name);
+    end
+}
+
+/// Removes the entry at `offset` from the `size` bytes of `directory`, moving later
+/// entries down over it, and returns its new size.
+fn pull_entry(directory: &mut [u8], size: usize, offset: usize) -> usize {
+    let len = ENTRY_HEADER_BYTES + directory[offset] as usize;
+    directory.copy_within(offset + len..size, offset);
+    directory[size - len..size].fill(0);
+    size - len
+}
+
+/// Extends the CRC32 (IEEE) `crc` of some bytes to cover `bytes` as well; start from 0.
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Checksum-table entry for a block holding `buffer`. Never 0, which marks an
+/// unchecked block.
+fn block_checksum(buffer: &[u8]) -> u32 {
+    crc32(0, buffer).max(1)
+}
+
+/// Checksum a journal commit record carries over the list of home blocks `targets`
+/// and the `copies` staged for them, so a commit torn by a crash is recognised.
+fn journal_checksum<B: AsRef<[u8]>>(targets: &[u8], copies: &[B]) -> u32 {
+    let mut crc = crc32(0, targets);
+    for copy in copies {
+        crc = crc32(crc, copy.as_ref());
+    }
+    crc
+}
+
+/// Whether the committed journal `header` and the `copies` read from the slots after
+/// it hold a whole transaction: one that fits the journal, lists only blocks outside
+/// it, and matches its checksum.
+fn journal_intact<B: AsRef<[u8]>>(geometry: Geometry, header: &[u8], copies: &[B]) -> bool {
+    let len = header[1] as usize;
+    if len > JOURNAL_CAPACITY || copies.len() != len {
+        return false;
+    }
+    let targets = &header[JOURNAL_HEADER_BYTES..JOURNAL_HEADER_BYTES + len];
+    let recorded = u32::from_be_bytes(header[2..JOURNAL_HEADER_BYTES].try_into().unwrap());
+    let journal = geometry.journal_start()..geometry.checksum_start();
+    recorded == journal_checksum(targets, copies)
+        && targets.iter().all(|target| {
+            (*target as usize) < geometry.num_blocks && !journal.contains(&(*target as usize))
+        })
+}
+
+// An attribute block holds records of a name length byte, a value length byte, the
+// name and the value, packed from the start of the block. A zero name length ends it.
+
+/// Offset just past the last attribute record in `block`.
+fn xattr_end(block: &[u8]) -> usize {
+    let mut start = 0;
+    while start + 2 <= block.len() && block[start] != 0 {
+        start += 2 + block[start] as usize + block[start + 1] as usize;
+    }
+    start
+}
+
+/// Offset of the record for attribute `name` in `block`.
+fn xattr_find(block: &[u8], name: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while start < xattr_end(block) {
+        let name_len = block[start] as usize;
+        if &block[start + 2..start + 2 + name_len] == name {
+            return Some(start);
+        }
+        start += 2 + name_len + block[start + 1] as usize;
+    }
+    None
+}
+
+/// Deletes the record at `start`, sliding later records down to fill the gap.
+fn xattr_remove(block: &mut [u8], start: usize) {
+    let record_len = 2 + block[start] as usize + block[start + 1] as usize;
+    let end = xattr_end(block);
+    block.copy_within(start + record_len..end, start);
+    block[end - record_len..end].fill(0);
+}
+
+/// Whether `name` matches the glob `pattern` as a whole. `*` matches any run of
+/// characters, `?` any one character, and `[...]` any one character in the class,
+/// which may hold ranges like `a-z` and is negated by a leading `!` or `^`. A `[`
+/// without a closing `]` matches itself. Names never contain `/`, so a path pattern
+/// can be matched one component at a time.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (mut pattern_rest, mut name_rest) = (pattern, name);
+    // Where to resume after the last `*` if the rest fails to match: the pattern
+    // after it, and the name with one more character given to the `*`.
+    let mut resume: Option<(&str, &str)> = None;
+    loop {
+        let mut pattern_chars = pattern_rest.chars();
+        let mut name_chars = name_rest.chars();
+        match pattern_chars.next() {
+            Some('*') => {
+                pattern_rest = pattern_chars.as_str();
+                resume = Some((pattern_rest, name_rest));
+                continue;
+            }
+            Some(wanted) => {
+                if let Some(c) = name_chars.next() {
+                    let (matched, rest) = match wanted {
+                        '?' => (true, pattern_chars.as_str()),
+                        '[' => match glob_class(pattern_chars.as_str(), c) {
+                            Some(result) => result,
+                            None => (c == '[', pattern_chars.as_str()),
+                        },
+                        _ => (c == wanted, pattern_chars.as_str()),
+                    };
+                    if matched {
+                        pattern_rest = rest;
+                        name_rest = name_chars.as_str();
+                        continue;
+                    }
+                }
+            }
+            None if name_rest.is_empty() => return true,
+            None => {}
+        }
+        let Some((after_star, starred)) = resume else {
+            return false;
+        };
+        let mut starred = starred.chars();
+        if starred.next().is_none() {
+            return false;
+        }
+        pattern_rest = after_star;
+        name_rest = starred.as_str();
+        resume = Some((pattern_rest, name_rest));
+    }
+}
+
+/// Matches `c` against the class that `class` opens (just past its `[`), returning
+/// the result and the pattern after the closing `]`, or None if it's never closed.
+fn glob_class(class: &str, c: char) -> Option<(bool, &str)> {
+    let (negated, mut rest) = match class.strip_prefix(['!', '^']) {
+        Some(rest) => (true, rest),
+        None => (false, class),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut chars = rest.chars();
+        let low = chars.next()?;
+        // A `]` straight after the `[` is part of the class rather than its end.
+        if low == ']' && !first {
+            return Some((matched != negated, chars.as_str()));
+        }
+        first = false;
+        rest = chars.as_str();
+        let mut range = chars.clone();
+        if let (Some('-'), Some(high)) = (range.next(), range.next()) {
+            if high != ']' {
+                matched |= (low..=high).contains(&c);
+                rest = range.as_str();
+                continue;
+            }
+        }
+        matched |= low == c;
+    }
+}
+
+//Here are some sample unit tests. For this assignment, you will be running the file system entirely through unit tests. Part of the assignment is to write unit tests sufficient to demonstrate that it works.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 64;
+    const MAX_FILES_STORED: usize = 32;
+
+    fn make_small_fs() -> FileSystem<16, 64, 255, 8, 512, 32, 8> {
+        FileSystem::new(ramdisk::RamDisk::new())
+    }
+
+    /// Wraps a disk, logging every block written. Once `crash_after` writes have gone
+    /// through the power "fails": that write is dropped, or with `tear` set only its
+    /// first half lands, and nothing written afterwards reaches the disk. Discarded
+    /// blocks are logged too, and scribbled over like an erase.
+    struct FaultDisk {
+        disk: ramdisk::RamDisk<64, 255>,
+        writes: Vec<usize>,
+        discards: Vec<(usize, usize)>,
+        crash_after: Option<usize>,
+        tear: bool,
+    }
+
+    impl FaultDisk {
+        fn new(crash_after: Option<usize>, tear: bool) -> Self {
+            Self {
+                disk: ramdisk::RamDisk::new(),
+                writes: Vec::new(),
+                discards: Vec::new(),
+                crash_after,
+                tear,
+            }
+        }
+    }
+
+    impl BlockDevice<64> for FaultDisk {
+        fn read(&mut self, block: usize, buffer: &mut [u8; 64]) {
+            self.disk.read(block, buffer);
+        }
+
+        fn write(&mut self, block: usize, buffer: &[u8; 64]) {
+            let issued = self.writes.len();
+            self.writes.push(block);
+            match self.crash_after {
+                Some(limit) if issued > limit => {}
+                Some(limit) if issued == limit => {
+                    if self.tear {
+                        let mut torn = [0; 64];
+                        self.disk.read(block, &mut torn);
+                        torn[..32].copy_from_slice(&buffer[..32]);
+                        self.disk.write(block, &torn);
+                    }
+                }
+                _ => self.disk.write(block, buffer),
+            }
+        }
+
+        fn discard(&mut self, start: usize, count: usize) {
+            self.discards.push((start, count));
+            if self
+                .crash_after
+                .is_none_or(|limit| self.writes.len() <= limit)
+            {
+                for block in start..start + count {
+                    self.disk.write(block, &[0xA5; 64]);
+                }
+            }
+        }
+    }
+
+    type DeviceFs<D> = FileSystem<16, 64, 255, 8, 512, 32, 8, CounterClock, D>;
+
+    #[test]
+    fn test_short_write() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        let mut buffer = [0; 50];
+        sys.close(f1).unwrap();
+        let f2 = sys.open_read("one.txt").unwrap();
+        let bytes_read = sys.read(f2, &mut buffer).unwrap();
+        assert_eq!(bytes_read, 15);
+        let s = core::str::from_utf8(&buffer[0..bytes_read]).unwrap();
+        assert_eq!(s, "This is a test.");
+    }
+
+    const LONG_DATA: &str = "This is a much, much longer message.
+    It crosses a number of different lines in the text editor, all synthesized
+    with the goal of exceeding the 64 byte block limit by a considerable amount.
+    To that end, this text contains considerable excessive verbiage.";
+
+    #[test]
+    fn test_long_write() {
+        assert_eq!(265, LONG_DATA.len());
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, LONG_DATA.as_bytes()).unwrap();
+        sys.close(f1);
+        let read = read_to_string(&mut sys, "one.txt");
+        assert_eq!(read.as_str(), LONG_DATA);
+    }
+
+    fn read_to_string<C: Clock, D: BlockDevice<BLOCK_SIZE>>(
+        sys: &mut FileSystem<16, BLOCK_SIZE, 255, 8, 512, 32, 8, C, D>,
+        filename: &str,
+    ) -> String {
+        let fd = sys.open_read(filename).unwrap();
+        let mut read = String::new();
+        let mut buffer = [0; 10];
+        loop {
+            let num_bytes = sys.read(fd, &mut buffer).unwrap();
+            let s = core::str::from_utf8(&buffer[0..num_bytes]).unwrap();
+            read.push_str(s);
+            if num_bytes < buffer.len() {
+                sys.close(fd).unwrap();
+                return read;
+            }
+        }
+    }
+
+    #[test]
+    fn test_complex_1() {
+        let one = "This is a message, a short message, but an increasingly long message.
+        This is a message, a short message, but an increasingly long message.";
+        let two = "This is the second message I have chosen to undertake in this particular test.
+        This is a continuation of this ever-so-controversial second message.\n";
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, one[0..one.len() / 2].as_bytes()).unwrap();
+        let f2 = sys.open_create("two.txt").unwrap();
+        sys.write(f2, two[0..two.len() / 2].as_bytes()).unwrap();
+        sys.write(f1, one[one.len() / 2..one.len()].as_bytes())
+            .unwrap();
+        sys.write(f2, two[two.len() / 2..two.len()].as_bytes())
+            .unwrap();
+        sys.close(f1).unwrap();
+        sys.close(f2).unwrap();
+        assert_eq!(one, read_to_string(&mut sys, "one.txt").as_str());
+        assert_eq!(two, read_to_string(&mut sys, "two.txt").as_str());
+    }
+
+    #[test]
+    fn test_complex_2() {
+        let one = "This is a message, a short message, but an increasingly long message.
+        This is a message, a short message, but an increasingly long message.";
+        let two = "This is the second message I have chosen to undertake in this particular test.
+        This is a continuation of this ever-so-controversial second message.\n";
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, one[0..one.len() / 2].as_bytes()).unwrap();
+        let f2 = sys.open_create("two.txt").unwrap();
+        sys.write(f2, two[0..two.len() / 2].as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        sys.close(f2).unwrap();
+
+        let f3 = sys.open_append("two.txt").unwrap();
+        let f4 = sys.open_append("one.txt").unwrap();
+        sys.write(f4, one[one.len() / 2..one.len()].as_bytes())
+            .unwrap();
+        sys.write(f3, two[two.len() / 2..two.len()].as_bytes())
+            .unwrap();
+        sys.close(f1).unwrap();
+        sys.close(f2).unwrap();
+        assert_eq!(one, read_to_string(&mut sys, "one.txt").as_str());
+        assert_eq!(two, read_to_string(&mut sys, "two.txt").as_str());
+    }
+
+    #[test]
+    fn test_complex_3() {
+        let one = "This is a message, a short message, but an increasingly long message.
+        This is a message, a short message, but an increasingly long message.";
+        let two = "This is the second message I have chosen to undertake in this particular test.
+        This is a continuation of this ever-so-controversial second message.\n";
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, one.as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+
+        let f2 = sys.open_create("one.txt").unwrap();
+        sys.write(f2, two.as_bytes()).unwrap();
+        sys.close(f2).unwrap();
+
+        assert_eq!(two, read_to_string(&mut sys, "one.txt").as_str());
+    }
+
+    #[test]
+    fn test_file_not_found() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        match sys.open_read("one.tx") {
+            FileSystemResult::Ok(_) => panic!("Shouldn't have found the file"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FileNotFound),
+        }
+    }
+
+    #[test]
+    fn test_file_not_open() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let fd = sys.open_read("one.txt").unwrap();
+        let mut buffer = [0; 10];
+        match sys.read(fd + 1, &mut buffer) {
+            FileSystemResult::Ok(_) => panic!("Should be an error!"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FileNotOpen),
+        }
+    }
+
+    #[test]
+    fn test_not_open_for_read() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        let mut buffer = [0; 10];
+        match sys.read(f1, &mut buffer) {
+            FileSystemResult::Ok(_) => panic!("Should not work!"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::NotOpenForRead),
+        }
+    }
+    #[test]
+    fn test_not_open_for_write() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let f2 = sys.open_read("one.txt").unwrap();
+        match sys.write(f2, "this is also a test".as_bytes()) {
+            FileSystemResult::Ok(_) => panic!("Should be an error"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::NotOpenForWrite),
+        }
+    }
+
+    #[test]
+    fn test_filename_too_long() {
+        let mut sys = make_small_fs();
+        match sys.open_create("this_is_an_exceedingly_long_filename_to_use.txt") {
+            FileSystemResult::Ok(_) => panic!("This should be an error"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FilenameTooLong),
+        }
+    }
+
+    #[test]
+    fn test_already_open() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        match sys.open_read("one.txt") {
+            FileSystemResult::Ok(_) => panic!("Should be an error"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::AlreadyOpen),
+        }
+    }
+
+    #[test]
+    fn test_file_too_big() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        for _ in 0..sys.max_file_size() - 1 {
+            sys.write(f1, "A".as_bytes()).unwrap();
+        }
+        match sys.write(f1, "B".as_bytes()) {
+            FileSystemResult::Ok(_) => panic!("Should be an error!"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FileTooBig),
+        }
+    }
+
+    #[test]
+    fn test_too_many_files() {
+        let mut sys = make_small_fs();
+        for i in 0..MAX_FILES_STORED - 1 {
+            let filename = format!("file{i}");
+            let f = sys.open_create(filename.as_str()).unwrap();
+            let content = format!("This is sentence {i}");
+            sys.write(f, content.as_bytes()).unwrap();
+            sys.close(f).unwrap();
+        }
+        match sys.open_create("Final") {
+            FileSystemResult::Ok(_) => panic!("This should be an error!"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::TooManyFiles),
+        }
+    }
+
+    #[test]
+    fn test_filename_validation() {
+        let mut sys = make_small_fs();
+        assert!(sys.open_create("") == FileSystemResult::Err(FileSystemError::EmptyFilename));
+        for name in ["a/b", "a\0b"] {
+            assert!(
+                sys.open_create(name) == FileSystemResult::Err(FileSystemError::InvalidFilename)
+            );
+        }
+        // Limits are in bytes, so a name of 8 characters may still be too long.
+        assert!(
+            sys.open_create("éééééééé") == FileSystemResult::Err(FileSystemError::FilenameTooLong)
+        );
+
+        for name in ["héllo", "a", "日本"] {
+            let fd = sys.open_create(name).unwrap();
+            sys.write(fd, name.as_bytes()).unwrap();
+            sys.close(fd).unwrap();
+        }
+        sys.delete("a").unwrap();
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(sys.into_disk());
+        assert_eq!(read_to_string(&mut sys, "héllo"), "héllo");
+        assert_eq!(read_to_string(&mut sys, "日本"), "日本");
+        assert!(sys.open_read("a") == FileSystemResult::Err(FileSystemError::FileNotFound));
+    }
+
+    #[test]
+    fn test_hard_link_shares_data() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        sys.link("one.txt", "two.txt").unwrap();
+
+        let f2 = sys.open_append("two.txt").unwrap();
+        sys.write(f2, " More.".as_bytes()).unwrap();
+        sys.close(f2).unwrap();
+        assert_eq!(
+            "This is a test. More.",
+            read_to_string(&mut sys, "one.txt").as_str()
+        );
+
+        let f3 = sys.open_read("one.txt").unwrap();
+        match sys.open_read("two.txt") {
+            FileSystemResult::Ok(_) => panic!("Both names refer to one open inode"),
+            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::AlreadyOpen),
+        }
+        sys.close(f3).unwrap();
+    }
+
+    #[test]
+    fn test_create_without_free_descriptor() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("keep").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let fds: Vec<usize> = (0..16)
+            .map(|i| sys.open_create(format!("f{i}").as_str()).unwrap())
+            .collect();
+        assert!(sys.open_create("keep") == FileSystemResult::Err(FileSystemError::TooManyOpen));
+        assert!(sys.open_create("stray") == FileSystemResult::Err(FileSystemError::TooManyOpen));
+        for fd in fds {
+            sys.close(fd).unwrap();
+        }
+        assert_eq!("This is a test.", read_to_string(&mut sys, "keep").as_str());
+        assert!(sys.open_read("stray") == FileSystemResult::Err(FileSystemError::FileNotFound));
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.close(f1).unwrap();
+        let f2 = sys.open_create("two.txt").unwrap();
+        sys.close(f2).unwrap();
+        assert!(
+            sys.link("missing", "three") == FileSystemResult::Err(FileSystemError::FileNotFound)
+        );
+        assert!(
+            sys.link("one.txt", "two.txt") == FileSystemResult::Err(FileSystemError::FileExists)
+        );
+        assert!(
+            sys.link("one.txt", "much_too_long")
+                == FileSystemResult::Err(FileSystemError::FilenameTooLong)
+        );
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.close(fd).unwrap();
+        sys.chmod("one.txt", 0o600, Credentials::ROOT).unwrap();
+        let free_before = sys.num_free_blocks().unwrap();
+
+        sys.copy("one.txt", "two.txt").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before - 5);
+        assert_eq!(read_to_string(&mut sys, "two.txt"), LONG_DATA);
+        assert_eq!(sys.stat("two.txt").unwrap().mode, 0o600);
+        let fd = sys.open_create("two.txt").unwrap();
+        sys.write(fd, b"changed").unwrap();
+        sys.close(fd).unwrap();
+        assert_eq!(read_to_string(&mut sys, "one.txt"), LONG_DATA);
+
+        assert!(
+            sys.copy("one.txt", "two.txt") == FileSystemResult::Err(FileSystemError::FileExists)
+        );
+        assert!(
+            sys.copy("missing", "three.txt")
+                == FileSystemResult::Err(FileSystemError::FileNotFound)
+        );
+        assert!(sys.fsck(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_copy_failure_leaves_nothing() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.close(fd).unwrap();
+        let inode_num = sys.lookup("one.txt").unwrap();
+        let block = sys.read_inode(inode_num).unwrap().blocks[3] as usize;
+        flip_bit(&mut sys, block);
+        let free_before = sys.num_free_blocks().unwrap();
+
+        assert!(
+            sys.copy("one.txt", "two.txt")
+                == FileSystemResult::Err(FileSystemError::Corrupted { block })
+        );
+        assert!(sys.open_read("two.txt") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        sys.delete("one.txt").unwrap();
+        let fd = sys.open_create("big").unwrap();
+        sys.write(fd, &[7; 500]).unwrap();
+        sys.close(fd).unwrap();
+        let mut i = 0;
+        while sys.num_free_blocks().unwrap() >= 8 {
+            sys.copy("big", &format!("f{i}")).unwrap();
+            i += 1;
+        }
+        let free_before = sys.num_free_blocks().unwrap();
+        assert!(sys.copy("big", "last") == FileSystemResult::Err(FileSystemError::DiskFull));
+        assert!(sys.open_read("last") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+        assert!(sys.fsck(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_reflink_copies_on_write() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.close(fd).unwrap();
+        let free_before = sys.num_free_blocks().unwrap();
+
+        sys.reflink("one.txt", "two.txt").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+        assert_eq!(read_to_string(&mut sys, "two.txt"), LONG_DATA);
+        let one = sys.lookup("one.txt").unwrap();
+        let two = sys.lookup("two.txt").unwrap();
+        assert_eq!(
+            sys.read_inode(one).unwrap().blocks,
+            sys.read_inode(two).unwrap().blocks
+        );
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        // Appending only unshares the last block.
+        let fd = sys.open_append("two.txt").unwrap();
+        sys.write(fd, b"!").unwrap();
+        sys.close(fd).unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before - 1);
+        assert_eq!(read_to_string(&mut sys, "one.txt"), LONG_DATA);
+        assert_eq!(read_to_string(&mut sys, "two.txt"), format!("{LONG_DATA}!"));
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        sys.delete("one.txt").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+        assert_eq!(read_to_string(&mut sys, "two.txt"), format!("{LONG_DATA}!"));
+        assert!(sys.fsck(false).unwrap().is_clean());
+        sys.delete("two.txt").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before + 5);
+        assert!(sys.fsck(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_delete_frees_blocks_after_last_link() {
+        let mut sys = make_small_fs();
+        let free_before = sys.num_free_blocks().unwrap();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, LONG_DATA.as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        sys.link("one.txt", "two.txt").unwrap();
+        let free_with_file = sys.num_free_blocks().unwrap();
+
+        sys.delete("one.txt").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_with_file);
+        assert_eq!(LONG_DATA, read_to_string(&mut sys, "two.txt").as_str());
+        assert!(sys.open_read("one.txt") == FileSystemResult::Err(FileSystemError::FileNotFound));
+
+        sys.delete("two.txt").unwrap();
+        // The directory keeps the block it grew into.
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before - 1);
+        let (count, _) = sys.list_directory().unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_delete_while_open_defers_free() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, LONG_DATA.as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let free_with_file = sys.num_free_blocks().unwrap();
+
+        let fd = sys.open_read("one.txt").unwrap();
+        sys.delete("one.txt").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_with_file);
+        let mut buffer = [0; 15];
+        assert_eq!(sys.read(fd, &mut buffer).unwrap(), 15);
+        assert_eq!(&buffer, &LONG_DATA.as_bytes()[..15]);
+        sys.close(fd).unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_with_file + 5);
+
+        let f2 = sys.open_create("two.txt").unwrap();
+        sys.close(f2).unwrap();
+        let (count, files) = sys.list_directory().unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(&files[0][..7], "two.txt".as_bytes());
+    }
+
+    #[test]
+    fn test_discard_freed_blocks() {
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new(FaultDisk::new(None, false));
+        for name in ["one", "two"] {
+            let fd = sys.open_create(name).unwrap();
+            sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+            sys.close(fd).unwrap();
+        }
+        assert!(sys.disk().discards.is_empty());
+
+        // A file's blocks are discarded as one run once the delete commits.
+        let inode_num = sys.lookup("one").unwrap();
+        let first = sys.read_inode(inode_num).unwrap().blocks[0] as usize;
+        sys.delete("one").unwrap();
+        assert_eq!(sys.disk().discards, [(first, 5)]);
+
+        // Blocks still shared with a reflink stay.
+        sys.reflink("two", "three").unwrap();
+        sys.delete("three").unwrap();
+        assert_eq!(sys.disk().discards.len(), 1);
+
+        let fd = sys.open_create("two").unwrap();
+        assert_eq!(sys.disk().discards.len(), 2);
+        sys.write(fd, b"short").unwrap();
+        sys.close(fd).unwrap();
+        assert_eq!(read_to_string(&mut sys, "two"), "short");
+        assert!(sys.fsck(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_read_dir() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.symlink("one.txt", "link").unwrap();
+        let two = sys.open_create("two.txt").unwrap();
+        sys.close(two).unwrap();
+        sys.delete("two.txt").unwrap();
+        let one = sys.lookup("one.txt").unwrap();
+
+        // one.txt is still open, so its size comes from the descriptor.
+        let entries: Vec<_> = sys.read_dir().unwrap().collect();
+        // lookup would follow the link, so take its inode from the listing.
+        let link = entries[1].inode;
+        assert_ne!(link, one);
+        let expected = [
+            DirEntry {
+                name: "one.txt",
+                inode: one,
+                file_type: FileType::File,
+                len: LONG_DATA.len(),
+            },
+            DirEntry {
+                name: "link",
+                inode: link,
+                file_type: FileType::Symlink,
+                len: "one.txt".len(),
+            },
+        ];
+        assert_eq!(entries, expected);
+        sys.close(fd).unwrap();
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(sys.into_disk());
+        assert_eq!(sys.read_dir().unwrap().collect::<Vec<_>>(), expected);
+        // The iterator only borrows; it holds no per-file state of its own.
+        assert_eq!(
+            core::mem::size_of::<ReadDir>(),
+            core::mem::size_of::<Entries>() + core::mem::size_of::<&[u8]>()
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, name) in [
+            ("log*.txt", "log.txt"),
+            ("log*.txt", "log1.txt"),
+            ("*", ""),
+            ("*a*b", "xaxxab"),
+            ("?é?", "aéb"),
+            ("[a-c]x", "bx"),
+            ("[!a-c]x", "dx"),
+            ("[]]", "]"),
+            ("[a-]", "-"),
+            ("a[", "a["),
+        ] {
+            assert!(glob_match(pattern, name), "{pattern} should match {name}");
+        }
+        for (pattern, name) in [
+            ("log*.txt", "log.txt.bak"),
+            ("log*.txt", "alog.txt"),
+            ("?", ""),
+            ("??", "é"),
+            ("[a-c]x", "dx"),
+            ("[^a-c]x", "ax"),
+            ("a*b*c", "abbb"),
+        ] {
+            assert!(
+                !glob_match(pattern, name),
+                "{pattern} shouldn't match {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let mut sys = make_small_fs();
+        for name in ["log1.txt", "log2.txt", "logs", "a.txt", "log3.md"] {
+            let fd = sys.open_create(name).unwrap();
+            sys.close(fd).unwrap();
+        }
+        let names = |sys: &mut FileSystem<16, 64, 255, 8, 512, 32, 8>, pattern| {
+            let found: Vec<_> = sys.find(pattern).unwrap().map(|entry| entry.name).collect();
+            found.join(" ")
+        };
+        assert_eq!(names(&mut sys, "log*.txt"), "log1.txt log2.txt");
+        assert_eq!(names(&mut sys, "log*"), "log1.txt log2.txt logs log3.md");
+        assert_eq!(names(&mut sys, "log[13].*"), "log1.txt log3.md");
+        assert_eq!(names(&mut sys, "nothing*"), "");
+        let first = sys.find("a.*").unwrap().next().unwrap();
+        assert_eq!(first.inode, sys.lookup("a.txt").unwrap());
+    }
+
+    #[test]
+    fn test_symlink_follows_target() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        sys.symlink("one.txt", "latest").unwrap();
+        sys.symlink("latest", "current").unwrap();
+        assert_eq!(
+            "This is a test.",
+            read_to_string(&mut sys, "current").as_str()
+        );
+
+        let mut buffer = [0; 16];
+        let len = sys.readlink("current", &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], "latest".as_bytes());
+        assert!(
+            sys.readlink("one.txt", &mut buffer)
+                == FileSystemResult::Err(FileSystemError::NotASymlink)
+        );
+
+        let f2 = sys.open_append("latest").unwrap();
+        sys.write(f2, " Appended.".as_bytes()).unwrap();
+        sys.close(f2).unwrap();
+        assert_eq!(
+            "This is a test. Appended.",
+            read_to_string(&mut sys, "one.txt").as_str()
+        );
+    }
+
+    #[test]
+    fn test_symlink_dangling_create() {
+        let mut sys = make_small_fs();
+        sys.symlink("target", "link").unwrap();
+        assert!(sys.open_read("link") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        let f1 = sys.open_create("link").unwrap();
+        sys.write(f1, "Made through a link.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        assert_eq!(
+            "Made through a link.",
+            read_to_string(&mut sys, "target").as_str()
+        );
+
+        sys.delete("link").unwrap();
+        assert_eq!(
+            "Made through a link.",
+            read_to_string(&mut sys, "target").as_str()
+        );
+    }
+
+    #[test]
+    fn test_symlink_loop() {
+        let mut sys = make_small_fs();
+        sys.symlink("b", "a").unwrap();
+        sys.symlink("a", "b").unwrap();
+        assert!(sys.open_read("a") == FileSystemResult::Err(FileSystemError::TooManySymlinks));
+        assert!(sys.open_create("b") == FileSystemResult::Err(FileSystemError::TooManySymlinks));
+        assert!(sys.symlink("c", "a") == FileSystemResult::Err(FileSystemError::FileExists));
+    }
+
+    struct ManualClock {
+        time: u32,
+    }
+
+    impl Clock for ManualClock {
+        fn now(&mut self) -> u32 {
+            self.time
+        }
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        let created = sys.stat("one.txt").unwrap();
+        assert_eq!(created.ctime, created.mtime);
+        assert_eq!(created.ctime, created.atime);
+
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let written = sys.stat("one.txt").unwrap();
+        assert_eq!(written.ctime, created.ctime);
+        assert!(written.mtime > created.mtime);
+        assert_eq!(written.atime, created.atime);
+        assert_eq!(written.size, 15);
+        assert_eq!(written.file_type, FileType::File);
+
+        read_to_string(&mut sys, "one.txt");
+        let read = sys.stat("one.txt").unwrap();
+        assert_eq!(read.mtime, written.mtime);
+        assert!(read.atime > written.mtime);
+    }
+
+    #[test]
+    fn test_custom_clock() {
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8, ManualClock> =
+            FileSystem::with_clock(ramdisk::RamDisk::new(), ManualClock { time: 100 });
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.clock.time = 250;
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let stat = sys.stat("one.txt").unwrap();
+        assert_eq!((stat.ctime, stat.mtime, stat.atime), (100, 250, 100));
+    }
+
+    #[test]
+    fn test_permissions() {
+        let alice = Credentials { uid: 1, gid: 10 };
+        let bob = Credentials { uid: 2, gid: 10 };
+        let eve = Credentials { uid: 3, gid: 30 };
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create_as("notes", alice).unwrap();
+        sys.write(f1, "Private.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let stat = sys.stat("notes").unwrap();
+        assert_eq!((stat.mode, stat.uid, stat.gid), (0o644, 1, 10));
+
+        let f2 = sys.open_read_as("notes", bob).unwrap();
+        sys.close(f2).unwrap();
+        assert!(
+            sys.open_append_as("notes", bob)
+                == FileSystemResult::Err(FileSystemError::PermissionDenied)
+        );
+        assert!(
+            sys.open_create_as("notes", eve)
+                == FileSystemResult::Err(FileSystemError::PermissionDenied)
+        );
+
+        sys.chmod("notes", 0o600, alice).unwrap();
+        assert!(
+            sys.open_read_as("notes", bob)
+                == FileSystemResult::Err(FileSystemError::PermissionDenied)
+        );
+        let f3 = sys.open_append_as("notes", alice).unwrap();
+        sys.close(f3).unwrap();
+        let f4 = sys.open_read("notes").unwrap();
+        sys.close(f4).unwrap();
+        assert_eq!("Private.", read_to_string(&mut sys, "notes").as_str());
+    }
+
+    #[test]
+    fn test_directory_write_needed() {
+        let alice = Credentials { uid: 1, gid: 10 };
+        let bob = Credentials { uid: 2, gid: 20 };
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create_as("notes", alice).unwrap();
+        sys.close(f1).unwrap();
+        sys.symlink_as("notes", "alias", bob).unwrap();
+        let offset = sys.directory.find_entry(b"alias").unwrap();
+        let link = sys.read_inode(sys.directory.entry_inode(offset)).unwrap();
+        assert_eq!((link.uid, link.gid), (2, 20));
+
+        let mut dir_inode = sys.read_inode(DIRECTORY_INODE).unwrap();
+        dir_inode.mode = 0o755;
+        sys.write_inode(DIRECTORY_INODE, &dir_inode).unwrap();
+        sys.commit_transaction().unwrap();
+        let denied = FileSystemResult::Err(FileSystemError::PermissionDenied);
+        assert!(sys.link_as("notes", "copy", bob) == denied);
+        assert!(sys.symlink_as("notes", "other", bob) == denied);
+        assert!(sys.delete_as("notes", alice) == denied);
+        assert!(
+            sys.open_create_as("new", bob)
+                == FileSystemResult::Err(FileSystemError::PermissionDenied)
+        );
+        sys.link("notes", "copy").unwrap();
+        sys.delete_as("alias", Credentials::ROOT).unwrap();
+        assert_eq!(sys.read_dir().unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_sticky_directory() {
+        let alice = Credentials { uid: 1, gid: 10 };
+        let bob = Credentials { uid: 2, gid: 20 };
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create_as("secret", alice).unwrap();
+        sys.write(f1, "Mine.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        sys.chmod("secret", 0o600, alice).unwrap();
+        let f2 = sys.open_create_as("scratch", bob).unwrap();
+        sys.close(f2).unwrap();
+
+        let denied = FileSystemResult::Err(FileSystemError::PermissionDenied);
+        assert!(sys.delete_as("secret", bob) == denied);
+        assert!(sys.delete_as("scratch", alice) == denied);
+        assert_eq!("Mine.", read_to_string(&mut sys, "secret").as_str());
+        sys.delete_as("scratch", bob).unwrap();
+        sys.delete_as("secret", alice).unwrap();
+        assert_eq!(sys.read_dir().unwrap().count(), 0);
+
+        let f3 = sys.open_create_as("secret", alice).unwrap();
+        sys.close(f3).unwrap();
+        sys.delete_as("secret", Credentials::ROOT).unwrap();
+        assert!(
+            sys.delete_as("secret", alice) == FileSystemResult::Err(FileSystemError::FileNotFound)
+        );
+    }
+
+    #[test]
+    fn test_chmod_chown_rules() {
+        let alice = Credentials { uid: 1, gid: 10 };
+        let bob = Credentials { uid: 2, gid: 20 };
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create_as("notes", alice).unwrap();
+        sys.close(f1).unwrap();
+        assert!(
+            sys.chmod("notes", 0o666, bob)
+                == FileSystemResult::Err(FileSystemError::PermissionDenied)
+        );
+        assert!(
+            sys.chown("notes", 2, 20, alice)
+                == FileSystemResult::Err(FileSystemError::PermissionDenied)
+        );
+        sys.chown("notes", 2, 20, Credentials::ROOT).unwrap();
+        sys.chmod("notes", 0o600, bob).unwrap();
+        assert!(
+            sys.open_read_as("notes", alice)
+                == FileSystemResult::Err(FileSystemError::PermissionDenied)
+        );
+        let stat = sys.stat("notes").unwrap();
+        assert_eq!((stat.mode, stat.uid, stat.gid), (0o600, 2, 20));
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, "This is a test.".as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        let free_before = sys.num_free_blocks().unwrap();
+
+        sys.set_xattr("one.txt", "type", "text/plain".as_bytes())
+            .unwrap();
+        sys.set_xattr("one.txt", "origin", "upload".as_bytes())
+            .unwrap();
+        sys.set_xattr("one.txt", "type", "text/markdown".as_bytes())
+            .unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before - 1);
+
+        let mut buffer = [0; 32];
+        let len = sys.get_xattr("one.txt", "type", &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], "text/markdown".as_bytes());
+        let len = sys.list_xattrs("one.txt", &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], "origin\0type\0".as_bytes());
+        assert_eq!(
+            "This is a test.",
+            read_to_string(&mut sys, "one.txt").as_str()
+        );
+
+        sys.remove_xattr("one.txt", "origin").unwrap();
+        assert!(
+            sys.get_xattr("one.txt", "origin", &mut buffer)
+                == FileSystemResult::Err(FileSystemError::XattrNotFound)
+        );
+        sys.remove_xattr("one.txt", "type").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+        assert_eq!(sys.list_xattrs("one.txt", &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_xattr_permissions() {
+        let alice = Credentials { uid: 1, gid: 10 };
+        let bob = Credentials { uid: 2, gid: 20 };
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create_as("notes", alice).unwrap();
+        sys.close(f1).unwrap();
+        sys.set_xattr_as("notes", "tag", b"mine", alice).unwrap();
+        let denied = FileSystemResult::Err(FileSystemError::PermissionDenied);
+        assert!(sys.set_xattr_as("notes", "tag", b"ours", bob) == denied);
+        assert!(sys.remove_xattr_as("notes", "tag", bob) == denied);
+        let mut buffer = [0; 8];
+        assert!(sys.get_xattr_as("notes", "tag", &mut buffer, bob) == FileSystemResult::Ok(4));
+        assert_eq!(&buffer[..4], b"mine");
+        sys.chmod("notes", 0o600, alice).unwrap();
+        let denied_len = FileSystemResult::Err(FileSystemError::PermissionDenied);
+        assert!(sys.get_xattr_as("notes", "tag", &mut buffer, bob) == denied_len);
+        assert!(sys.list_xattrs_as("notes", &mut buffer, bob) == denied_len);
+        assert!(sys.list_xattrs_as("notes", &mut buffer, alice) == FileSystemResult::Ok(4));
+        assert_eq!(&buffer[..4], b"tag\0");
+        sys.remove_xattr_as("notes", "tag", alice).unwrap();
+    }
+
+    #[test]
+    fn test_xattr_limits() {
+        let mut sys = make_small_fs();
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.close(f1).unwrap();
+        let long_name = "n".repeat(MAX_XATTR_NAME_BYTES + 1);
+        let long_value = [b'v'; MAX_XATTR_VALUE_BYTES + 1];
+        assert!(
+            sys.set_xattr("one.txt", "", b"x")
+                == FileSystemResult::Err(FileSystemError::InvalidXattrName)
+        );
+        assert!(
+            sys.set_xattr("one.txt", &long_name, b"x")
+                == FileSystemResult::Err(FileSystemError::XattrNameTooLong)
+        );
+        assert!(
+            sys.set_xattr("one.txt", "sum", &long_value)
+                == FileSystemResult::Err(FileSystemError::XattrValueTooLong)
+        );
+
+        let value = [b'v'; MAX_XATTR_VALUE_BYTES];
+        sys.set_xattr("one.txt", "a", &value).unwrap();
+        assert!(
+            sys.set_xattr("one.txt", "b", &value)
+                == FileSystemResult::Err(FileSystemError::XattrSpaceFull)
+        );
+        sys.set_xattr("one.txt", "a", b"short").unwrap();
+        sys.set_xattr("one.txt", "b", &value).unwrap();
+
+        let free_with_attrs = sys.num_free_blocks().unwrap();
+        sys.delete("one.txt").unwrap();
+        // Only the attribute block; the empty file has no data block.
+        assert_eq!(sys.num_free_blocks().unwrap(), free_with_attrs + 1);
+    }
+
+    #[test]
+    fn test_fsck_clean() {
+        let mut sys = make_small_fs();
+        assert!(sys.fsck(false).unwrap().is_clean());
+        let f1 = sys.open_create("one.txt").unwrap();
+        sys.write(f1, LONG_DATA.as_bytes()).unwrap();
+        sys.close(f1).unwrap();
+        sys.link("one.txt", "two.txt").unwrap();
+        sys.symlink("two.txt", "three").unwrap();
+        sys.set_xattr("one.txt", "type", b"text").unwrap();
+        let fd = sys.open_read("one.txt").unwrap();
+        sys.delete("one.txt").unwrap();
+        sys.delete("two.txt").unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+        sys.close(fd).unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_fsck_finds_and_repairs() {
+        let mut sys = make_small_fs();
+        for name in ["one", "two", "three"] {
+            let fd = sys.open_create(name).unwrap();
+            sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+            sys.close(fd).unwrap();
+        }
+        let one = sys.stat("one").unwrap().inode;
+        let two = sys.stat("two").unwrap().inode;
+        let three = sys.stat("three").unwrap().inode;
+
+        sys.alloc_block().unwrap();
+        let orphan = sys.alloc_inode().unwrap();
+        let mut inode = Inode::new();
+        inode.links = 1;
+        sys.write_inode(orphan, &inode).unwrap();
+        sys.set_bit(INODE_FULL_BLOCK, three, false).unwrap();
+
+        let mut inode = sys.read_inode(one).unwrap();
+        let stolen = inode.blocks[4];
+        inode.links = 3;
+        inode.bytes_stored = (8 * BLOCK_SIZE + 1) as u16;
+        sys.write_inode(one, &inode).unwrap();
+        let mut inode = sys.read_inode(two).unwrap();
+        inode.blocks[4] = stolen;
+        sys.write_inode(two, &inode).unwrap();
+
+        let report = sys.fsck(false).unwrap();
+        assert_eq!(report.orphaned_inodes, 1);
+        assert_eq!(report.dangling_names, 1);
+        assert_eq!(report.wrong_link_counts, 1);
+        assert_eq!(report.oversized_files, 1);
+        assert_eq!(report.duplicate_blocks, 1);
+        // The leaked block, two.txt's replaced block, and three.txt's five blocks.
+        assert_eq!(report.unreferenced_blocks, 7);
+        assert_eq!(sys.fsck(false).unwrap(), report);
+
+        sys.fsck(true).unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert!(sys.open_read("three") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        assert_eq!(sys.stat("one").unwrap().links, 1);
+        assert_eq!(sys.stat("one").unwrap().size, 8 * BLOCK_SIZE);
+        assert!(read_to_string(&mut sys, "one").starts_with(LONG_DATA));
+        // Losing the duplicate leaves a hole in two.txt.
+        assert_eq!(sys.stat("two").unwrap().size, LONG_DATA.len());
+        assert_eq!(sys.stat("two").unwrap().blocks, 4);
+    }
+
+    #[test]
+    fn test_fsck_repairs_refcounts() {
+        let mut sys = make_small_fs();
+        for name in ["one", "three"] {
+            let fd = sys.open_create(name).unwrap();
+            sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+            sys.close(fd).unwrap();
+        }
+        sys.reflink("one", "two").unwrap();
+        let one = sys.lookup("one").unwrap();
+        let three = sys.lookup("three").unwrap();
+        let shared = sys.read_inode(one).unwrap().blocks[0];
+        let private = sys.read_inode(three).unwrap().blocks[0];
+        sys.set_block_refs(shared, 3).unwrap();
+        sys.set_block_refs(private, 2).unwrap();
+        sys.commit_transaction().unwrap();
+
+        let report = sys.fsck(false).unwrap();
+        assert_eq!(report.wrong_refcounts, 2);
+        assert_eq!(
+            report,
+            FsckReport {
+                wrong_refcounts: 2,
+                ..FsckReport::default()
+            }
+        );
+        sys.fsck(true).unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert_eq!(sys.block_refs(shared).unwrap(), 2);
+        assert_eq!(sys.block_refs(private).unwrap(), 1);
+        for name in ["one", "two", "three"] {
+            assert_eq!(read_to_string(&mut sys, name), LONG_DATA);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_rollback() {
+        let mut sys = make_small_fs();
+        let free_before = sys.num_free_blocks().unwrap();
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.close(fd).unwrap();
+        sys.set_xattr("one.txt", "tag", b"old").unwrap();
+        sys.snapshot_create("before").unwrap();
+        let snapshots = sys.snapshot_list().unwrap();
+        assert_eq!(snapshots[0].unwrap().name(), "before");
+        assert!(snapshots[1..].iter().all(|snapshot| snapshot.is_none()));
+
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.write(fd, b"changed").unwrap();
+        sys.close(fd).unwrap();
+        sys.set_xattr("one.txt", "tag", b"new").unwrap();
+        let fd = sys.open_create("two.txt").unwrap();
+        sys.close(fd).unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        sys.snapshot_mount("before").unwrap();
+        assert_eq!(read_to_string(&mut sys, "one.txt"), LONG_DATA);
+        assert!(sys.open_read("two.txt") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        assert!(sys.open_create("three.txt") == FileSystemResult::Err(FileSystemError::ReadOnly));
+        assert!(sys.delete("one.txt") == FileSystemResult::Err(FileSystemError::ReadOnly));
+        sys.snapshot_unmount().unwrap();
+        assert_eq!(read_to_string(&mut sys, "one.txt"), "changed");
+
+        let fd = sys.open_read("two.txt").unwrap();
+        assert!(
+            sys.snapshot_rollback("before") == FileSystemResult::Err(FileSystemError::FilesOpen)
+        );
+        sys.close(fd).unwrap();
+        sys.snapshot_rollback("before").unwrap();
+        assert_eq!(read_to_string(&mut sys, "one.txt"), LONG_DATA);
+        let mut value = [0; 3];
+        sys.get_xattr("one.txt", "tag", &mut value).unwrap();
+        assert_eq!(&value, b"old");
+        assert!(sys.open_read("two.txt") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        // Rolled back, the live file system shares everything with the snapshot again.
+        sys.snapshot_delete("before").unwrap();
+        assert!(sys
+            .snapshot_list()
+            .unwrap()
+            .iter()
+            .all(|snapshot| snapshot.is_none()));
+        assert!(sys.fsck(false).unwrap().is_clean());
+        sys.delete("one.txt").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before - 1);
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let mut sys = make_small_fs();
+        for i in 0..MAX_SNAPSHOTS {
+            sys.snapshot_create(&format!("s{i}")).unwrap();
+        }
+        assert!(
+            sys.snapshot_create("s0") == FileSystemResult::Err(FileSystemError::SnapshotExists)
+        );
+        assert!(
+            sys.snapshot_create("extra")
+                == FileSystemResult::Err(FileSystemError::TooManySnapshots)
+        );
+        for name in ["extra", "s"] {
+            assert!(
+                sys.snapshot_delete(name)
+                    == FileSystemResult::Err(FileSystemError::SnapshotNotFound)
+            );
+        }
+        sys.snapshot_delete("s3").unwrap();
+        sys.snapshot_create("extra").unwrap();
+        assert_eq!(sys.snapshot_list().unwrap()[3].unwrap().name(), "extra");
+        assert!(sys.fsck(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_snapshot_skips_deleted_open_file() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one.txt").unwrap();
+        let free_before = sys.num_free_blocks().unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.delete("one.txt").unwrap();
+        sys.snapshot_create("snap").unwrap();
+        sys.close(fd).unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        sys.snapshot_rollback("snap").unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert!(sys.open_read("one.txt") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        sys.snapshot_delete("snap").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+    }
+
+    /// Fills the disk with files whose blocks are interleaved, so they're spread over
+    /// every reference-count block, then frees blocks scattered across it so each
+    /// copy a snapshot makes needs its own checksum-table block.
+    fn fragment<C: Clock, D: BlockDevice<BLOCK_SIZE>>(
+        sys: &mut FileSystem<16, 64, 255, 8, 512, 32, 8, C, D>,
+    ) {
+        let block = [b'x'; BLOCK_SIZE];
+        for _ in 0..7 {
+            for i in 0..24 {
+                let name = format!("f{i}");
+                let fd = match sys.open_append(&name) {
+                    FileSystemResult::Ok(fd) => fd,
+                    FileSystemResult::Err(_) => sys.open_create(&name).unwrap(),
+                };
+                sys.write(fd, &block).unwrap();
+                sys.close(fd).unwrap();
+            }
+        }
+        for i in [0, 12] {
+            sys.delete(&format!("f{i}")).unwrap();
+        }
+    }
+
+    /// The worst case for a snapshot takes more blocks than the journal holds.
+    #[test]
+    fn test_snapshot_of_fragmented_disk() {
+        let mut sys = make_small_fs();
+        fragment(&mut sys);
+        let free_before = sys.num_free_blocks().unwrap();
+        sys.snapshot_create("snap").unwrap();
+        assert_eq!(sys.snapshot_list().unwrap()[0].unwrap().name(), "snap");
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        sys.delete("f1").unwrap();
+        sys.snapshot_rollback("snap").unwrap();
+        assert_eq!(read_to_string(&mut sys, "f1").len(), 7 * BLOCK_SIZE);
+        sys.snapshot_delete("snap").unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+    }
+
+    /// A snapshot cut short anywhere either never started or is finished by the next
+    /// mount.
+    #[test]
+    fn test_snapshot_finishes_after_crash() {
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new(FaultDisk::new(None, false));
+        fragment(&mut sys);
+        let start = sys.disk.writes.len();
+        sys.snapshot_create("snap").unwrap();
+        let end = sys.disk.writes.len();
+
+        let mut finished = 0;
+        for crash_after in start..end {
+            let mut sys: DeviceFs<FaultDisk> =
+                FileSystem::new(FaultDisk::new(Some(crash_after), false));
+            fragment(&mut sys);
+            let _ = sys.snapshot_create("snap");
+            let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(sys.disk.disk);
+            assert!(sys.fsck(false).unwrap().is_clean());
+            if sys.snapshot_list().unwrap()[0].is_some() {
+                sys.delete("f1").unwrap();
+                sys.snapshot_rollback("snap").unwrap();
+                assert_eq!(read_to_string(&mut sys, "f1").len(), 7 * BLOCK_SIZE);
+                assert!(sys.fsck(false).unwrap().is_clean());
+                finished += 1;
+            }
+        }
+        assert!(finished > (end - start) / 2);
+    }
+
+    /// A snapshot deletion cut short anywhere either never started or is finished by
+    /// the next mount.
+    #[test]
+    fn test_snapshot_delete_finishes_after_crash() {
+        let setup = |sys: &mut DeviceFs<FaultDisk>| {
+            fragment(sys);
+            sys.snapshot_create("snap").unwrap();
+            // Most blocks are then the snapshot's alone.
+            for i in 1..12 {
+                sys.delete(&format!("f{i}")).unwrap();
+            }
+        };
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new(FaultDisk::new(None, false));
+        setup(&mut sys);
+        let free_before = sys.num_free_blocks().unwrap();
+        let start = sys.disk.writes.len();
+        sys.snapshot_delete("snap").unwrap();
+        let end = sys.disk.writes.len();
+        let free_after = sys.num_free_blocks().unwrap();
+
+        let mut finished = 0;
+        for crash_after in start..end {
+            let mut sys: DeviceFs<FaultDisk> =
+                FileSystem::new(FaultDisk::new(Some(crash_after), false));
+            setup(&mut sys);
+            let _ = sys.snapshot_delete("snap");
+            let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(sys.disk.disk);
+            assert!(sys.fsck(false).unwrap().is_clean());
+            if sys.snapshot_list().unwrap()[0].is_none() {
+                assert_eq!(sys.num_free_blocks().unwrap(), free_after);
+                finished += 1;
+            } else {
+                assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+            }
+        }
+        assert!(finished > (end - start) / 2);
+    }
+
+    /// Stages the metadata of a new file named "two" on top of a committed "one", then
+    /// stops after writing the journal, as if power failed before the checkpoint.
+    fn crash_after_journal_write() -> ramdisk::RamDisk<64, 255> {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one").unwrap();
+        sys.write(fd, b"first").unwrap();
+        sys.close(fd).unwrap();
+        sys.create_inode(b"two", FileType::File, Credentials::ROOT, 1)
+            .unwrap();
+        sys.write_journal();
+        sys.disk
+    }
+
+    #[test]
+    fn test_read_only_mount() {
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new(FaultDisk::new(None, false));
+        let fd = sys.open_create("one").unwrap();
+        sys.write(fd, b"data").unwrap();
+        sys.close(fd).unwrap();
+        let mut disk = sys.into_disk();
+        disk.writes.clear();
+
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new_read_only(disk);
+        assert_eq!(read_to_string(&mut sys, "one"), "data");
+        assert!(sys.fsck(false).unwrap().is_clean());
+        let read_only = FileSystemError::ReadOnly;
+        assert!(sys.open_create("two") == FileSystemResult::Err(read_only));
+        assert!(sys.open_create("one") == FileSystemResult::Err(read_only));
+        assert!(sys.open_append("one") == FileSystemResult::Err(read_only));
+        assert!(sys.delete("one") == FileSystemResult::Err(read_only));
+        assert!(sys.set_xattr("one", "tag", b"x") == FileSystemResult::Err(read_only));
+        assert!(sys.snapshot_create("snap") == FileSystemResult::Err(read_only));
+        assert!(sys.fsck(true) == FileSystemResult::Err(read_only));
+        let fd = sys.open_read("one").unwrap();
+        assert!(sys.write(fd, b"more") == FileSystemResult::Err(FileSystemError::NotOpenForWrite));
+        sys.close(fd).unwrap();
+        assert!(sys.into_disk().writes.is_empty());
+    }
+
+    #[test]
+    fn test_read_only_mount_sees_journal() {
+        let disk = FaultDisk {
+            disk: crash_after_journal_write(),
+            writes: Vec::new(),
+            discards: Vec::new(),
+            crash_after: None,
+            tear: false,
+        };
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new_read_only(disk);
+        assert_eq!(read_to_string(&mut sys, "one"), "first");
+        sys.stat("two").unwrap();
+        let disk = sys.into_disk();
+        assert!(disk.writes.is_empty());
+
+        // The transaction is still in the journal for a writable mount to replay.
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new(disk);
+        assert!(!sys.disk().writes.is_empty());
+        sys.stat("two").unwrap();
+    }
+
+    #[test]
+    fn test_journal_replays_committed_transaction() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one").unwrap();
+        sys.write(fd, b"first").unwrap();
+        sys.close(fd).unwrap();
+        let mut header = [0; BLOCK_SIZE];
+        sys.disk.read(sys.journal_start(), &mut header);
+        assert_eq!(header, [0; BLOCK_SIZE]);
+
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> =
+            FileSystem::new(crash_after_journal_write());
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert_eq!(sys.stat("two").unwrap().links, 1);
+        assert_eq!(read_to_string(&mut sys, "one"), "first");
+        sys.disk.read(sys.journal_start(), &mut header);
+        assert_eq!(header, [0; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn test_journal_discards_torn_commit() {
+        let mut disk = crash_after_journal_write();
+        let journal_start = make_small_fs().journal_start();
+        let mut block = [0; BLOCK_SIZE];
+        disk.read(journal_start + 1, &mut block);
+        block[0] ^= 0xff;
+        disk.write(journal_start + 1, &block);
+
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(disk);
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert!(sys.stat("two") == FileSystemResult::Err(FileSystemError::FileNotFound));
+        assert_eq!(read_to_string(&mut sys, "one"), "first");
+    }
+
+    /// Copying blocks on write stages a reference count and a checksum-table block for
+    /// each, more than the journal holds when the blocks are small and scattered.
+    #[test]
+    fn test_copy_on_write_of_scattered_blocks() {
+        let mut sys: FileSystem<16, 32, 255, 8, 256, 18, 8> =
+            FileSystem::new(ramdisk::RamDisk::new());
+        // Eight blocks each, the last one short of full.
+        for len in [32, 32, 32, 32, 32, 32, 32, 31] {
+            for i in 0..16 {
+                let name = format!("f{i}");
+                let fd = match sys.open_append(&name) {
+                    FileSystemResult::Ok(fd) => fd,
+                    FileSystemResult::Err(_) => sys.open_create(&name).unwrap(),
+                };
+                sys.write(fd, &[b'x'; 32][..len]).unwrap();
+                sys.close(fd).unwrap();
+            }
+        }
+        for i in [0, 8] {
+            sys.delete(&format!("f{i}")).unwrap();
+        }
+        sys.reflink("f1", "copy").unwrap();
+        let fd = sys.open_append("copy").unwrap();
+        sys.seek(fd, 0).unwrap();
+        sys.write(fd, &[b'y'; 255]).unwrap();
+        sys.close(fd).unwrap();
+        assert!(sys.fsck(false).unwrap().is_clean());
+        let mut buffer = [0; 255];
+        for (name, byte) in [("f1", b'x'), ("copy", b'y')] {
+            let fd = sys.open_read(name).unwrap();
+            assert_eq!(sys.read(fd, &mut buffer).unwrap(), buffer.len());
+            assert!(buffer.iter().all(|b| *b == byte));
+            sys.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_directory_index() {
+        let mut sys = make_small_fs();
+        let name = |i: usize| format!("f{i}");
+        for round in 0..3 {
+            for i in 0..MAX_FILES_STORED - 1 {
+                let fd = sys.open_create(&name(i)).unwrap();
+                sys.write(fd, name(i + round).as_bytes()).unwrap();
+                sys.close(fd).unwrap();
+            }
+            // Remove every other name, then check both halves still resolve correctly,
+            // through the live index and through one rebuilt from disk.
+            for i in (0..MAX_FILES_STORED - 1).step_by(2) {
+                sys.delete(&name(i)).unwrap();
+            }
+            for _ in 0..2 {
+                for i in 0..MAX_FILES_STORED - 1 {
+                    if i % 2 == 0 {
+                        assert!(
+                            sys.open_read(&name(i))
+                                == FileSystemResult::Err(FileSystemError::FileNotFound)
+                        );
+                    } else {
+                        assert_eq!(read_to_string(&mut sys, &name(i)), name(i + round));
+                    }
+                }
+                sys = FileSystem::new(sys.into_disk());
+            }
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    /// Flips a bit of `block` behind the file system's back.
+    fn flip_bit(sys: &mut FileSystem<16, 64, 255, 8, 512, 32, 8>, block: usize) {
+        let mut buffer = [0; BLOCK_SIZE];
+        sys.disk.read(block, &mut buffer);
+        buffer[BLOCK_SIZE / 2] ^= 1;
+        sys.disk.write(block, &buffer);
+    }
 
-            bytes_read +=1;
-        }
-        self.open[fd] = Some(file_to_read_from);
-        return FileSystemResult::Ok(bytes_read);
-        
+    #[test]
+    fn test_corrupted_data_block() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.close(fd).unwrap();
+        let inode_num = sys.lookup("one").unwrap();
+        let block = sys.read_inode(inode_num).unwrap().blocks[2] as usize;
+        flip_bit(&mut sys, block);
+
+        let fd = sys.open_read("one").unwrap();
+        let mut buffer = [0; 500];
+        assert!(
+            sys.read(fd, &mut buffer)
+                == FileSystemResult::Err(FileSystemError::Corrupted { block })
+        );
+        sys.close(fd).unwrap();
+
+        // Rewriting the block in place gives it a fresh checksum.
+        let fd = sys.open_create("one").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.close(fd).unwrap();
+        assert_eq!(read_to_string(&mut sys, "one"), LONG_DATA);
     }
 
-    pub fn write(&mut self, fd: usize, buffer: &[u8]) -> FileSystemResult<()> {
-        let mut pre_file_to_write_to = self.open[fd];
-       
-        if pre_file_to_write_to.is_none(){
-            return FileSystemResult::Err(FileSystemError::FileNotOpen)
-        }
-        let mut file_to_write_to = pre_file_to_write_to.unwrap();
-        if file_to_write_to.reading{
-            return FileSystemResult::Err(FileSystemError::NotOpenForWrite)
-        }
+    #[test]
+    fn test_corrupted_metadata() {
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one").unwrap();
+        sys.write(fd, b"data").unwrap();
+        sys.close(fd).unwrap();
+        let inode_num = sys.lookup("one").unwrap();
+        let (block, _) = sys.geometry().inode_location(inode_num);
+        flip_bit(&mut sys, block);
+        let corrupted = FileSystemError::Corrupted { block };
+        assert!(sys.open_read("one") == FileSystemResult::Err(corrupted));
+        assert!(sys.fsck(false) == FileSystemResult::Err(corrupted));
+        // The directory is cached once loaded, so it's only read again after a remount.
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(sys.into_disk());
+        assert!(sys.get_directory() == FileSystemResult::Err(corrupted));
 
-        let mut datatable = [0; BLOCK_SIZE];
+        let mut sys = make_small_fs();
+        let fd = sys.open_create("one").unwrap();
+        sys.close(fd).unwrap();
+        let block = sys.read_inode(DIRECTORY_INODE).unwrap().blocks[0] as usize;
+        flip_bit(&mut sys, block);
+        let corrupted = FileSystemError::Corrupted { block };
+        assert!(sys.fsck(false) == FileSystemResult::Err(corrupted));
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(sys.into_disk());
+        assert!(sys.list_directory() == FileSystemResult::Err(corrupted));
+        assert!(sys.open_create("two") == FileSystemResult::Err(corrupted));
+    }
 
-        self.disk.read(1,&mut datatable);
-       //println!("{:?}", datatable);
-        let mut block_full = true;
-        let mut num_blocks_count = 0;
-        for i in datatable{
-            if i != u8::MAX{
-                block_full = false;
-                break;
-            }
-            if num_blocks_count == NUM_BLOCKS/8{
-                block_full = true;
-                break;
-            }
-            num_blocks_count +=1;
+    /// Exercises every kind of metadata update. Results are ignored: after a simulated
+    /// crash the disk stops taking writes, so later steps may see a stale disk.
+    fn crash_workload<D: BlockDevice<64>>(sys: &mut DeviceFs<D>) {
+        if let FileSystemResult::Ok(fd) = sys.open_create("one") {
+            let _ = sys.write(fd, LONG_DATA.as_bytes());
+            let _ = sys.close(fd);
         }
-        if block_full{
-            return FileSystemResult::Err(FileSystemError::DiskFull)
+        let _ = sys.link("one", "two");
+        let _ = sys.copy("one", "four");
+        if let FileSystemResult::Ok(fd) = sys.open_append("four") {
+            let _ = sys.seek(fd, 7 * 64);
+            let _ = sys.write(fd, b"past a hole");
+            let _ = sys.close(fd);
         }
-
-        let inode_start = file_to_write_to.inode_num*self.num_inode_bytes() + 2;
-        let mut block_full_flag = false;
-        let mut blocks = [0;MAX_FILE_BLOCKS];
-        let mut count = 0;
-        let mut block_count = 2;
-        let mut c = 0;
-        let mut bytes_written = 0;
-        for i in inode_start..inode_start+self.num_inode_bytes()-2{
-            if !(blocks.contains(&self.file_content_buffer[i])){
-                if count == MAX_FILE_BLOCKS{
-                    return FileSystemResult::Err(FileSystemError::FileTooBig)
-                }
-                blocks[count] = self.file_content_buffer[i];
-                count +=1;
-            }
+        let _ = sys.reflink("one", "five");
+        if let FileSystemResult::Ok(fd) = sys.open_append("five") {
+            let _ = sys.write(fd, b"more");
+            let _ = sys.close(fd);
         }
-        if !(blocks.contains(&0)){
-            block_full_flag = true;
+        let _ = sys.symlink("two", "three");
+        let _ = sys.set_xattr("one", "user.tag", b"value");
+        let _ = sys.snapshot_create("snap");
+        if let FileSystemResult::Ok(fd) = sys.open_create("three") {
+            let _ = sys.write(fd, b"short");
+            let _ = sys.write(fd, b", then longer");
+            let _ = sys.close(fd);
         }
-        file_to_write_to.writing = true;
+        let _ = sys.delete("one");
+        let _ = sys.chmod("two", 0o600, Credentials::ROOT);
+        let _ = sys.delete("two");
+        let _ = sys.defragment(usize::MAX);
+        let _ = sys.snapshot_rollback("snap");
+        let _ = sys.snapshot_delete("snap");
+    }
 
-        
-        for i in buffer{
-            if block_full_flag{
-                let mut new_buffer = [0;BLOCK_SIZE];
-                self.disk.read(blocks[MAX_FILE_BLOCKS-1] as usize, &mut new_buffer);
-                if new_buffer[BLOCK_SIZE-2] != 0{
-                    return FileSystemResult::Err(FileSystemError::FileTooBig)
-                }
-            }
+    /// Crashes the workload at every write that reaches the `FaultDisk`, with and
+    /// without tearing that write, and checks each disk mounts to a consistent file
+    /// system. `mount` puts the file system on the disk; `crash` takes the disk back
+    /// as it stood at the crash, dropping anything held above it.
+    fn check_every_crash_point<D: BlockDevice<64>>(
+        mount: fn(FaultDisk) -> DeviceFs<D>,
+        crash: fn(DeviceFs<D>) -> FaultDisk,
+    ) {
+        let mut sys = mount(FaultDisk::new(None, false));
+        crash_workload(&mut sys);
+        let num_writes = crash(sys).writes.len();
+        assert!(num_writes > 50);
 
-            bytes_written +=1;
-            file_to_write_to.block_buffer[file_to_write_to.offset] = *i;
-            file_to_write_to.offset+=1;
-            c +=1;
-            if file_to_write_to.offset == BLOCK_SIZE{
-                self.disk.write(file_to_write_to.current_block, &file_to_write_to.block_buffer);
-                let new_block = self.return_open_data();
-                datatable[new_block[0] as usize] |= 1<< new_block[1];
-                self.disk.write(1, &datatable);
-                self.file_content_buffer = self.add_new_data_to_inode(file_to_write_to.inode_num as u8, new_block[2]);
-                file_to_write_to.current_block = new_block[2] as usize;
-                c = 0;
-                file_to_write_to.offset = 0;
-                block_count +=1;
-                file_to_write_to.block_buffer = [0; BLOCK_SIZE];
-                
+        for crash_after in 0..num_writes {
+            for tear in [false, true] {
+                let mut sys = mount(FaultDisk::new(Some(crash_after), tear));
+                crash_workload(&mut sys);
+                let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> =
+                    FileSystem::new(crash(sys).disk);
+                let report = sys.fsck(false).unwrap();
+                assert!(
+                    report.is_clean(),
+                    "crash after write {crash_after} (tear: {tear}): {report:?}"
+                );
             }
         }
-        pre_file_to_write_to = Some(file_to_write_to);
-
-        self.disk.write(file_to_write_to.current_block, &file_to_write_to.block_buffer);
-        //file_to_write_to.inode.bytes_stored +=bytes_written;
-        self.open[fd] = pre_file_to_write_to;
-
-        return FileSystemResult::Ok(());
     }
 
-    pub fn close(&mut self, fd: usize) -> FileSystemResult<()> {
-        // let mut file_to_close = self.open[fd].unwrap();
-        // self.open[fd] = None;
-        // self.open_inodes[file_to_close.inode_num];
-        // return FileSystemResult::Ok(());
-        let file = self.open[fd];
-        if file.is_none() {
-            return FileSystemResult::Err(FileSystemError::FileNotFound)    
-        } else {
-            let mut file = file.unwrap();
-            self.get_inode_table();
-            let inode_start = file.inode_num * self.num_inode_bytes();
-            let mut used_blocks = [self.file_content_buffer[inode_start + 2] as u8;MAX_FILE_BLOCKS];
-            let mut count = 1;
-            for i in (inode_start + 2)..(inode_start + self.num_inode_bytes()){
-                let value = self.file_content_buffer[i];
-                if used_blocks.contains(&value) {
-                } else{
-                    used_blocks[count] = value;
-                    count += 1;
-                }
-            }
-            let mut total_bytes = 0;
-           
-            for i in 0..count {
-                let mut buffer = [0; BLOCK_SIZE];
-                self.disk.read(used_blocks[i] as usize, &mut buffer);
-               
-                for j in buffer {
-                    if j != 0 {
-                        total_bytes += 1;
-                    } else{
-                        break;
-                    }
-                }
-            }
-           
-            let piece1 = (total_bytes >> 8) as u8;
-            let piece2 = total_bytes as u8;
-            self.file_content_buffer[inode_start] = piece1;
-            self.file_content_buffer[inode_start + 1] = piece2;
-            //println!("{:?}", self.file_content_buffer);
-            self.file_content_buffer = self.write_to_inode_table(2 + (inode_start / BLOCK_SIZE));
-            //println!("{:?}", self.file_content_buffer);
- 
-            self.open[fd] = None;
-            self.open_inodes[file.inode_num] = false;
-            return FileSystemResult::Ok(());
-        }
-    }
+    /// A rollback cut short anywhere either never started or is finished by the next
+    /// mount, so the files are all from one side of it.
+    #[test]
+    fn test_rollback_finishes_after_crash() {
+        let setup = |sys: &mut DeviceFs<FaultDisk>| {
+            let fd = sys.open_create("one").unwrap();
+            sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+            sys.close(fd).unwrap();
+            sys.snapshot_create("snap").unwrap();
+            let fd = sys.open_create("one").unwrap();
+            sys.write(fd, b"changed").unwrap();
+            sys.close(fd).unwrap();
+            let fd = sys.open_create("two").unwrap();
+            sys.close(fd).unwrap();
+        };
+        let mut sys: DeviceFs<FaultDisk> = FileSystem::new(FaultDisk::new(None, false));
+        setup(&mut sys);
+        let start = sys.disk.writes.len();
+        sys.snapshot_rollback("snap").unwrap();
+        let end = sys.disk.writes.len();
 
-    pub fn list_directory(&mut self) -> FileSystemResult<(usize, [[u8; MAX_FILENAME_BYTES]; MAX_FILES_STORED])> {
-        self.get_directory();
-        let mut count = 0;
-        let mut files = [['\0' as u8; MAX_FILENAME_BYTES]; MAX_FILES_STORED];
-        for (i, c) in self.directory_buffer.iter().enumerate() {
-            if i % MAX_FILENAME_BYTES == 0 && *c != 0 as u8 {
-                count += 1;
-                files[count - 1][i % MAX_FILENAME_BYTES] = *c;
-            } else if i % MAX_FILENAME_BYTES != 0{
-                files[count - 1][i % MAX_FILENAME_BYTES] = *c;
-            } else{
-                break;
+        let mut finished = 0;
+        for crash_after in start..end {
+            let mut sys: DeviceFs<FaultDisk> =
+                FileSystem::new(FaultDisk::new(Some(crash_after), false));
+            setup(&mut sys);
+            let _ = sys.snapshot_rollback("snap");
+            let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(sys.disk.disk);
+            assert!(sys.fsck(false).unwrap().is_clean());
+            if read_to_string(&mut sys, "one") == LONG_DATA {
+                assert!(
+                    sys.open_read("two") == FileSystemResult::Err(FileSystemError::FileNotFound)
+                );
+                finished += 1;
+            } else {
+                assert_eq!(read_to_string(&mut sys, "one"), "changed");
+                sys.open_read("two").unwrap();
             }
         }
-
-        return FileSystemResult::Ok((count, files))
+        assert!(finished > (end - start) / 2);
     }
-}
-
-//Here are some sample unit tests. For this assignment, you will be running the file system entirely through unit tests. Part of the assignment is to write unit tests sufficient to demonstrate that it works.
-
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    const BLOCK_SIZE: usize = 64;
-    const MAX_FILES_STORED: usize = 32;
 
-    fn make_small_fs() -> FileSystem<16, 64, 255, 8, 512, 32, 8> {
-        FileSystem::new(ramdisk::RamDisk::new())
+    #[test]
+    fn test_crash_at_every_write() {
+        check_every_crash_point(FileSystem::new, |sys| sys.disk);
     }
 
     #[test]
-    fn test_short_write() {
-        let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, "This is a test.".as_bytes()).unwrap();
-        let mut buffer = [0; 50];
-        sys.close(f1).unwrap();
-        let f2 = sys.open_read("one.txt").unwrap();
-        let bytes_read = sys.read(f2, &mut buffer).unwrap();
-        assert_eq!(bytes_read, 15);
-        let s = core::str::from_utf8(&buffer[0..bytes_read]).unwrap();
-        assert_eq!(s, "This is a test.");
+    fn test_crash_at_every_write_through_cache() {
+        check_every_crash_point(
+            |disk| FileSystem::new(BlockCache::<_, 64, 8>::new(disk)),
+            |sys| sys.disk.disk,
+        );
     }
-        
-    const LONG_DATA: &str = "This is a much, much longer message.
-    It crosses a number of different lines in the text editor, all synthesized
-    with the goal of exceeding the 64 byte block limit by a considerable amount.
-    To that end, this text contains considerable excessive verbiage.";
 
     #[test]
-    fn test_long_write() {
-        assert_eq!(265, LONG_DATA.len());
-        let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, LONG_DATA.as_bytes()).unwrap();
-        sys.close(f1);
-        let read = read_to_string(&mut sys, "one.txt");
-        assert_eq!(read.as_str(), LONG_DATA);
-    }
+    fn test_block_cache_write_back() {
+        let mut cache = BlockCache::<_, 64, 2>::new(FaultDisk::new(None, false));
+        let mut buffer = [0; 64];
+        for block in 0..3 {
+            cache.write(block, &[block as u8 + 1; 64]);
+        }
+        // Block 0 was evicted to make room for block 2; blocks 1 and 2 are only cached.
+        assert_eq!(cache.disk.writes, [0]);
+        cache.read(0, &mut buffer);
+        assert_eq!(buffer, [1; 64]);
+        cache.read(2, &mut buffer);
+        assert_eq!(buffer, [3; 64]);
+        assert_eq!((cache.hits(), cache.misses()), (1, 4));
 
-    fn read_to_string(
-        sys: &mut FileSystem<16, BLOCK_SIZE, 255, 8, 512, 32, 8>,
-        filename: &str,
-    ) -> String {
-        let fd = sys.open_read(filename).unwrap();
-        let mut read = String::new();
-        let mut buffer = [0; 10];
-        loop {
-            let num_bytes = sys.read(fd, &mut buffer).unwrap();
-            let s = core::str::from_utf8(&buffer[0..num_bytes]).unwrap();
-            read.push_str(s);
-            if num_bytes < buffer.len() {
-                sys.close(fd).unwrap();
-                return read;
-            }
+        let mut disk = cache.into_inner();
+        for block in 0..3 {
+            disk.read(block, &mut buffer);
+            assert_eq!(buffer, [block as u8 + 1; 64]);
         }
     }
 
     #[test]
-    fn test_complex_1() {
-        let one = "This is a message, a short message, but an increasingly long message.
-        This is a message, a short message, but an increasingly long message.";
-        let two = "This is the second message I have chosen to undertake in this particular test.
-        This is a continuation of this ever-so-controversial second message.\n";
-        let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, one[0..one.len() / 2].as_bytes()).unwrap();
-        let f2 = sys.open_create("two.txt").unwrap();
-        sys.write(f2, two[0..two.len() / 2].as_bytes()).unwrap();
-        sys.write(f1, one[one.len() / 2..one.len()].as_bytes())
-            .unwrap();
-        sys.write(f2, two[two.len() / 2..two.len()].as_bytes())
-            .unwrap();
-        sys.close(f1).unwrap();
-        sys.close(f2).unwrap();
-        assert_eq!(one, read_to_string(&mut sys, "one.txt").as_str());
-        assert_eq!(two, read_to_string(&mut sys, "two.txt").as_str());
+    fn test_sync_through_cache() {
+        let mut sys: DeviceFs<BlockCache<ramdisk::RamDisk<64, 255>, 64, 16>> =
+            FileSystem::new(BlockCache::new(ramdisk::RamDisk::new()));
+        let fd = sys.open_create("one").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.fsync(fd).unwrap();
+        sys.close(fd).unwrap();
+        assert!(sys.fsync(fd) == FileSystemResult::Err(FileSystemError::FileNotOpen));
+        for _ in 0..3 {
+            let fd = sys.open_read("one").unwrap();
+            let mut buffer = [0; 500];
+            assert_eq!(sys.read(fd, &mut buffer).unwrap(), LONG_DATA.len());
+            sys.close(fd).unwrap();
+        }
+        assert!(sys.disk().hits() > sys.disk().misses());
+
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> =
+            FileSystem::new(sys.into_disk().into_inner());
+        assert_eq!(read_to_string(&mut sys, "one"), LONG_DATA);
     }
 
     #[test]
-    fn test_complex_2() {
-        let one = "This is a message, a short message, but an increasingly long message.
-        This is a message, a short message, but an increasingly long message.";
-        let two = "This is the second message I have chosen to undertake in this particular test.
-        This is a continuation of this ever-so-controversial second message.\n";
+    fn test_inline_data() {
         let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, one[0..one.len() / 2].as_bytes()).unwrap();
-        let f2 = sys.open_create("two.txt").unwrap();
-        sys.write(f2, two[0..two.len() / 2].as_bytes()).unwrap();
-        sys.close(f1).unwrap();
-        sys.close(f2).unwrap();
+        let fd = sys.open_create("one.txt").unwrap();
+        // Only the directory has a block.
+        let free_before = sys.num_free_blocks().unwrap();
+        assert_eq!(free_before, sys.num_data_blocks() - 1);
+        sys.write(fd, b"tiny").unwrap();
+        sys.write(fd, b"!").unwrap();
+        sys.close(fd).unwrap();
+        sys.symlink("one.txt", "two").unwrap();
+        sys.copy("one.txt", "three").unwrap();
+        sys.reflink("one.txt", "four").unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+        assert_eq!(read_to_string(&mut sys, "two"), "tiny!");
+        assert_eq!(read_to_string(&mut sys, "four"), "tiny!");
 
-        let f3 = sys.open_append("two.txt").unwrap();
-        let f4 = sys.open_append("one.txt").unwrap();
-        sys.write(f4, one[one.len() / 2..one.len()].as_bytes())
-            .unwrap();
-        sys.write(f3, two[two.len() / 2..two.len()].as_bytes())
-            .unwrap();
-        sys.close(f1).unwrap();
-        sys.close(f2).unwrap();
-        assert_eq!(one, read_to_string(&mut sys, "one.txt").as_str());
-        assert_eq!(two, read_to_string(&mut sys, "two.txt").as_str());
+        // Outgrowing the inode moves the contents to a block.
+        let fd = sys.open_append("one.txt").unwrap();
+        sys.write(fd, b" not anymore").unwrap();
+        sys.close(fd).unwrap();
+        assert_eq!(read_to_string(&mut sys, "one.txt"), "tiny! not anymore");
+        assert_eq!(read_to_string(&mut sys, "three"), "tiny!");
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before - 1);
+        assert!(sys.fsck(false).unwrap().is_clean());
+
+        // Truncating frees the block again.
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.close(fd).unwrap();
+        assert_eq!(sys.num_free_blocks().unwrap(), free_before);
+        assert_eq!(read_to_string(&mut sys, "one.txt"), "");
     }
-    
+
     #[test]
-    fn test_complex_3() {
-        let one = "This is a message, a short message, but an increasingly long message.
-        This is a message, a short message, but an increasingly long message.";
-        let two = "This is the second message I have chosen to undertake in this particular test.
-        This is a continuation of this ever-so-controversial second message.\n";
+    fn test_sparse_file() {
         let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, one.as_bytes()).unwrap();
-        sys.close(f1).unwrap();
+        let fd = sys.open_create("one.txt").unwrap();
+        sys.seek(fd, 300).unwrap();
+        sys.write(fd, b"end").unwrap();
+        sys.seek(fd, 10).unwrap();
+        sys.write(fd, b"start").unwrap();
+        assert!(sys.seek(fd, 512) == FileSystemResult::Err(FileSystemError::FileTooBig));
+        sys.close(fd).unwrap();
+        let stat = sys.stat("one.txt").unwrap();
+        assert_eq!((stat.size, stat.blocks), (303, 2));
 
-        let f2 = sys.open_create("one.txt").unwrap();
-        sys.write(f2, two.as_bytes()).unwrap();
-        sys.close(f2).unwrap();
+        let mut expected = vec![0; 303];
+        expected[10..15].copy_from_slice(b"start");
+        expected[300..].copy_from_slice(b"end");
+        let fd = sys.open_read("one.txt").unwrap();
+        let mut buffer = [0xff; 400];
+        assert_eq!(sys.read(fd, &mut buffer).unwrap(), 303);
+        assert_eq!(&buffer[..303], &expected[..]);
+        sys.seek(fd, 299).unwrap();
+        assert_eq!(sys.read(fd, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer[..4], b"\0end");
+        sys.close(fd).unwrap();
 
-        assert_eq!(two, read_to_string(&mut sys, "one.txt").as_str());
+        // Filling part of a hole allocates just that block.
+        let fd = sys.open_append("one.txt").unwrap();
+        sys.seek(fd, 130).unwrap();
+        sys.write(fd, b"middle").unwrap();
+        sys.close(fd).unwrap();
+        assert_eq!(sys.stat("one.txt").unwrap().blocks, 3);
+        sys.copy("one.txt", "two.txt").unwrap();
+        assert_eq!(sys.stat("two.txt").unwrap().blocks, 3);
+        assert!(sys.fsck(false).unwrap().is_clean());
     }
 
     #[test]
-    fn test_file_not_found() {
+    fn test_defragment() {
         let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, "This is a test.".as_bytes()).unwrap();
-        sys.close(f1).unwrap();
-        match sys.open_read("one.tx") {
-            FileSystemResult::Ok(_) => panic!("Shouldn't have found the file"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FileNotFound),
+        let a = sys.open_create("a").unwrap();
+        let b = sys.open_create("b").unwrap();
+        for _ in 0..4 {
+            sys.write(a, &[b'a'; BLOCK_SIZE]).unwrap();
+            sys.write(b, &[b'b'; BLOCK_SIZE]).unwrap();
+        }
+        sys.close(a).unwrap();
+        sys.close(b).unwrap();
+        sys.delete("a").unwrap();
+        let c = sys.open_create("c").unwrap();
+        sys.write(c, &[b'c'; 2 * BLOCK_SIZE]).unwrap();
+        sys.close(c).unwrap();
+
+        let mut calls = 1;
+        while !sys.defragment(1).unwrap() {
+            calls += 1;
         }
+        // b's first block makes way for c's second, then four single moves.
+        assert_eq!(calls, 5);
+        // The directory's block comes first, then c and b back to back.
+        let first = sys.first_data_block() as u8 + 1;
+        let (c, b) = (sys.stat("c").unwrap().inode, sys.stat("b").unwrap().inode);
+        let (c, b) = (sys.read_inode(c).unwrap(), sys.read_inode(b).unwrap());
+        assert_eq!(c.blocks[..2], [first, first + 1]);
+        assert_eq!(b.blocks[..4], [first + 2, first + 3, first + 4, first + 5]);
+        assert_eq!(read_to_string(&mut sys, "b"), "b".repeat(4 * BLOCK_SIZE));
+        assert_eq!(read_to_string(&mut sys, "c"), "c".repeat(2 * BLOCK_SIZE));
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert!(sys.defragment(0).unwrap());
     }
 
     #[test]
-    fn test_file_not_open() {
+    fn test_convert() {
         let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, "This is a test.".as_bytes()).unwrap();
-        sys.close(f1).unwrap();
-        let fd = sys.open_read("one.txt").unwrap();
-        let mut buffer = [0; 10];
-        match sys.read(fd + 1, &mut buffer) {
-            FileSystemResult::Ok(_) => panic!("Should be an error!"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FileNotOpen),
+        for (name, contents) in [("long", LONG_DATA), ("tiny", "hi")] {
+            let fd = sys.open_create(name).unwrap();
+            sys.write(fd, contents.as_bytes()).unwrap();
+            sys.close(fd).unwrap();
         }
+        let fd = sys.open_create("sparse").unwrap();
+        sys.seek(fd, 300).unwrap();
+        sys.write(fd, b"end").unwrap();
+        sys.close(fd).unwrap();
+        sys.link("long", "again").unwrap();
+        sys.symlink("tiny", "link").unwrap();
+        sys.set_xattr("long", "user.tag", b"value").unwrap();
+        sys.chmod("tiny", 0o600, Credentials::ROOT).unwrap();
+
+        let mut big: FileSystem<16, 128, 200, 8, 1024, 48, 12> =
+            FileSystem::new(ramdisk::RamDisk::new());
+        sys.convert_to(&mut big).unwrap();
+        for name in ["long", "again", "sparse", "tiny", "link"] {
+            let (before, after) = (sys.stat(name).unwrap(), big.stat(name).unwrap());
+            assert_eq!(
+                (
+                    after.size,
+                    after.links,
+                    after.mode,
+                    after.ctime,
+                    after.mtime
+                ),
+                (
+                    before.size,
+                    before.links,
+                    before.mode,
+                    before.ctime,
+                    before.mtime
+                )
+            );
+        }
+        assert_eq!(big.stat("sparse").unwrap().blocks, 1);
+        let fd = big.open_read("again").unwrap();
+        let mut buffer = [0; 1024];
+        let len = big.read(fd, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], LONG_DATA.as_bytes());
+        big.close(fd).unwrap();
+        assert_eq!(big.readlink("link", &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer[..4], b"tiny");
+        assert_eq!(big.get_xattr("long", "user.tag", &mut buffer).unwrap(), 5);
+        assert!(big.fsck(false).unwrap().is_clean());
+        assert!(sys.convert_to(&mut big) == FileSystemResult::Err(FileSystemError::FileExists));
+
+        let mut few_files: FileSystem<16, 64, 255, 8, 512, 4, 8> =
+            FileSystem::new(ramdisk::RamDisk::new());
+        assert!(
+            sys.convert_to(&mut few_files) == FileSystemResult::Err(FileSystemError::TooManyFiles)
+        );
+        let mut short_names: FileSystem<16, 64, 255, 8, 512, 32, 4> =
+            FileSystem::new(ramdisk::RamDisk::new());
+        assert!(
+            sys.convert_to(&mut short_names)
+                == FileSystemResult::Err(FileSystemError::FilenameTooLong)
+        );
+        // Nothing is written to a target that's too small.
+        let mut small: FileSystem<16, 64, 36, 8, 512, 8, 8> =
+            FileSystem::new(ramdisk::RamDisk::new());
+        let free = small.num_free_blocks().unwrap();
+        assert!(sys.convert_to(&mut small) == FileSystemResult::Err(FileSystemError::DiskFull));
+        assert_eq!(small.num_free_blocks().unwrap(), free);
+        assert_eq!(small.read_dir().unwrap().count(), 0);
+        assert_eq!(few_files.read_dir().unwrap().count(), 0);
+
+        // The blocks counted up front are exactly the ones the copy takes.
+        let mut roomy: FileSystem<16, 64, 40, 8, 512, 8, 8> =
+            FileSystem::new(ramdisk::RamDisk::new());
+        let free = roomy.num_free_blocks().unwrap();
+        let geometry = Geometry::new(64, 40, 8, 8, 8);
+        sys.check_convert_fits(geometry, free).unwrap();
+        assert!(
+            sys.check_convert_fits(geometry, 7) == FileSystemResult::Err(FileSystemError::DiskFull)
+        );
+        sys.convert_to(&mut roomy).unwrap();
+        assert_eq!(roomy.num_free_blocks().unwrap(), free - 8);
+        assert!(roomy.fsck(false).unwrap().is_clean());
     }
 
     #[test]
-    fn test_not_open_for_read() {
-        let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, "This is a test.".as_bytes()).unwrap();
-        let mut buffer = [0; 10];
-        match sys.read(f1, &mut buffer) {
-            FileSystemResult::Ok(_) => panic!("Should not work!"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::NotOpenForRead),
+    fn test_geometry() {
+        let geometry = Geometry::new(64, 255, 8, 32, 8);
+        assert_eq!(geometry.check(), Ok(()));
+        let sys = make_small_fs();
+        assert_eq!(geometry.max_file_bytes(), 512);
+        assert_eq!(geometry.inode_bytes(), sys.num_inode_bytes());
+        assert_eq!(geometry.inode_blocks(), sys.num_inode_blocks());
+        assert_eq!(geometry.first_data_block(), sys.first_data_block());
+        assert_eq!(geometry.data_blocks(), sys.num_data_blocks());
+        assert_eq!(geometry.max_files_limit(), 46);
+
+        let mut derived: FileSystem<
+            16,
+            64,
+            255,
+            8,
+            { Geometry::new(64, 255, 8, 32, 8).max_file_bytes() },
+            32,
+            8,
+        > = FileSystem::new(ramdisk::RamDisk::new());
+        assert!(derived.fsck(false).unwrap().is_clean());
+
+        for (bad, problem) in [
+            (Geometry::new(62, 255, 8, 32, 8), "BLOCK_SIZE"),
+            (Geometry::new(64, 300, 8, 32, 8), "NUM_BLOCKS"),
+            (Geometry::new(64, 255, 8, 47, 8), "MAX_FILES_STORED"),
+            (Geometry::new(64, 255, 50, 32, 8), "inode must fit"),
+            (Geometry::new(64, 30, 8, 32, 8), "inode table"),
+            (Geometry::new(64, 40, 8, 32, 8), "data blocks"),
+            (Geometry::new(64, 255, 8, 4, 60), "snapshot root"),
+        ] {
+            assert!(bad.check().unwrap_err().contains(problem), "{bad:?}");
         }
     }
-    #[test]
-    fn test_not_open_for_write() {
-        let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, "This is a test.".as_bytes()).unwrap();
-        sys.close(f1).unwrap();
-        let f2 = sys.open_read("one.txt").unwrap();
-        match sys.write(f2, "this is also a test".as_bytes()) {
-            FileSystemResult::Ok(_) => panic!("Should be an error"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::NotOpenForWrite),
+
+    #[cfg(feature = "alloc")]
+    fn disk_image(mut disk: ramdisk::RamDisk<64, 255>) -> Vec<u8> {
+        let mut image = Vec::new();
+        let mut buffer = [0; 64];
+        for block in 0..255 {
+            BlockDevice::read(&mut disk, block, &mut buffer);
+            image.extend_from_slice(&buffer);
         }
+        image
     }
 
-    #[test]
-    fn test_filename_too_long() {
-        let mut sys = make_small_fs();
-        match sys.open_create("this_is_an_exceedingly_long_filename_to_use.txt") {
-            FileSystemResult::Ok(_) => panic!("This should be an error"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FilenameTooLong),
+    #[cfg(feature = "alloc")]
+    fn image_disk<const BS: usize, const NB: usize>(image: &[u8]) -> ramdisk::RamDisk<BS, NB> {
+        let mut disk = ramdisk::RamDisk::new();
+        for (block, contents) in image.chunks(BS).enumerate() {
+            BlockDevice::write(&mut disk, block, contents.try_into().unwrap());
         }
+        disk
     }
 
+    #[cfg(feature = "alloc")]
     #[test]
-    fn test_already_open() {
+    fn test_dyn_file_system() {
         let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        sys.write(f1, "This is a test.".as_bytes()).unwrap();
-        match sys.open_read("one.txt") {
-            FileSystemResult::Ok(_) => panic!("Should be an error"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::AlreadyOpen),
-        }
+        let fd = sys.open_create("long").unwrap();
+        sys.write(fd, LONG_DATA.as_bytes()).unwrap();
+        sys.close(fd).unwrap();
+        sys.reflink("long", "copy").unwrap();
+        sys.symlink("long", "link").unwrap();
+
+        let mut image = DynFileSystem::<CounterClock>::open(disk_image(sys.into_disk())).unwrap();
+        assert_eq!(image.geometry(), Geometry::new(64, 255, 8, 32, 8));
+        let names: Vec<&str> = image.read_dir().unwrap().map(|e| e.name).collect();
+        assert_eq!(names, ["long", "copy", "link"]);
+        assert_eq!(image.read_file("link").unwrap(), LONG_DATA.as_bytes());
+        assert_eq!(
+            image.stat("copy").unwrap().blocks,
+            LONG_DATA.len().div_ceil(64)
+        );
+
+        image.write_file("copy", b"short").unwrap();
+        let mut sparse = vec![0; 300];
+        sparse.extend_from_slice(b"end");
+        image.write_file("sparse", &sparse).unwrap();
+        image.delete("long").unwrap();
+        assert_eq!(image.stat("sparse").unwrap().blocks, 1);
+        assert_eq!(image.read_file("sparse").unwrap(), sparse);
+        assert_eq!(
+            image.write_file("huge", &[1; 512]),
+            Err(FileSystemError::FileTooBig)
+        );
+        image.write_file("full", &[1; 511]).unwrap();
+        image.delete("full").unwrap();
+        assert_eq!(image.read_file("link"), Err(FileSystemError::FileNotFound));
+        assert_eq!(image.delete("long"), Err(FileSystemError::FileNotFound));
+
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> =
+            FileSystem::new(image_disk(image.image()));
+        assert!(sys.fsck(false).unwrap().is_clean());
+        assert_eq!(read_to_string(&mut sys, "copy"), "short");
+        assert_eq!(sys.stat("sparse").unwrap().size, 303);
+
+        // A committed transaction left in the journal is finished on opening.
+        let mut image =
+            DynFileSystem::<CounterClock>::open(disk_image(crash_after_journal_write())).unwrap();
+        assert_eq!(image.read_file("one").unwrap(), b"first");
+        assert_eq!(image.stat("two").unwrap().links, 1);
     }
 
+    #[cfg(feature = "alloc")]
     #[test]
-    fn test_file_too_big() {
-        let mut sys = make_small_fs();
-        let f1 = sys.open_create("one.txt").unwrap();
-        for _ in 0..sys.max_file_size() - 1 {
-            sys.write(f1, "A".as_bytes()).unwrap();
-        }
-        match sys.write(f1, "B".as_bytes()) {
-            FileSystemResult::Ok(_) => panic!("Should be an error!"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::FileTooBig),
-        }
+    fn test_dyn_format() {
+        let geometry = Geometry::new(128, 200, 8, 48, 12);
+        let mut image = DynFileSystem::<CounterClock>::format(geometry).unwrap();
+        image
+            .write_file("a_long_name", LONG_DATA.as_bytes())
+            .unwrap();
+        assert_eq!(
+            image.write_file("a_longer_name", b""),
+            Err(FileSystemError::FilenameTooLong)
+        );
+
+        let mut sys: FileSystem<16, 128, 200, 8, 1024, 48, 12> =
+            FileSystem::new(image_disk(image.image()));
+        assert!(sys.fsck(false).unwrap().is_clean());
+        let fd = sys.open_read("a_long_name").unwrap();
+        let mut buffer = [0; 1024];
+        let len = sys.read(fd, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], LONG_DATA.as_bytes());
+
+        let mut image = image.into_image();
+        image.pop();
+        assert_eq!(
+            DynFileSystem::<CounterClock>::open(image).err(),
+            Some(FileSystemError::InvalidGeometry)
+        );
+        assert_eq!(
+            DynFileSystem::<CounterClock>::format(Geometry::new(64, 30, 8, 32, 8)).err(),
+            Some(FileSystemError::InvalidGeometry)
+        );
+        let mut corrupted = disk_image(make_small_fs().into_disk());
+        corrupted[INODE_TABLE_START * 64 + 2] ^= 2;
+        assert_eq!(
+            DynFileSystem::<CounterClock>::open(corrupted).err(),
+            Some(FileSystemError::Corrupted {
+                block: INODE_TABLE_START
+            })
+        );
     }
 
     #[test]
-    fn test_too_many_files() {
-        let mut sys = make_small_fs();
-        for i in 0..MAX_FILES_STORED - 1 {
-            let filename = format!("file{i}");
-            let f = sys.open_create(filename.as_str()).unwrap();
-            let content = format!("This is sentence {i}");
-            sys.write(f, content.as_bytes()).unwrap();
-            sys.close(f).unwrap();
-        }
-        match sys.open_create("Final") {
-            FileSystemResult::Ok(_) => panic!("This should be an error!"),
-            FileSystemResult::Err(e) => assert_eq!(e, FileSystemError::TooManyFiles),
+    fn test_unsupported_format() {
+        // Before the superblock, block 0 held the inode bitmap.
+        let mut old = ramdisk::RamDisk::<64, 255>::new();
+        let mut bitmap = [0; 64];
+        bitmap[0] = 0b11;
+        old.write(0, &bitmap);
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(old);
+        assert!(
+            sys.open_read("one.txt") == FileSystemResult::Err(FileSystemError::UnsupportedFormat)
+        );
+        assert!(
+            sys.open_create("one.txt") == FileSystemResult::Err(FileSystemError::UnsupportedFormat)
+        );
+        let disk = sys.into_disk();
+        let mut block = [0; 64];
+        disk.read(0, &mut block);
+        assert_eq!(block, bitmap);
+        for num in 1..255 {
+            disk.read(num, &mut block);
+            assert_eq!(block, [0; 64]);
         }
-    }
 
-    
+        let mut sys = make_small_fs();
+        sys.open_create("one.txt").unwrap();
+        let mut other: FileSystem<16, 64, 255, 8, 512, 16, 8> = FileSystem::new(sys.into_disk());
+        assert!(
+            other.open_read("one.txt") == FileSystemResult::Err(FileSystemError::UnsupportedFormat)
+        );
+        let mut sys: FileSystem<16, 64, 255, 8, 512, 32, 8> = FileSystem::new(other.into_disk());
+        assert!(sys.fsck(false).unwrap().is_clean());
+        sys.open_read("one.txt").unwrap();
+    }
 }